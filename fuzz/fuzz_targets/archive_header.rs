@@ -0,0 +1,9 @@
+#![no_main]
+//! Feeds arbitrary bytes to the archive header parser
+
+use libfuzzer_sys::fuzz_target;
+use spritezip::reader::read_archive_header;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = read_archive_header(&mut &data[..]);
+});
@@ -0,0 +1,27 @@
+#![no_main]
+//! Feeds arbitrary bytes to the bincode DecompressionInfo deserializer, then validates whatever it produced
+
+use libfuzzer_sys::fuzz_target;
+use spritezip::codec::Codec;
+use spritezip::common::{ArchiveHeader, FILE_FORMAT_VERSION};
+use spritezip::reader::deserialize_decompression_info;
+use spritezip::scan_order::DEFAULT_SCAN_ORDER;
+use spritezip::validate::{validate_decompression_info, ArchiveLimits};
+
+fuzz_target!(|data: &[u8]| {
+    let limits = ArchiveLimits::default();
+    if let Ok(decompression_info) = deserialize_decompression_info(data, FILE_FORMAT_VERSION, &limits) {
+        //pretend the metadata came from a 1MB archive
+        let header = ArchiveHeader {
+            version: FILE_FORMAT_VERSION,
+            image_codec: Codec::Brotli,
+            bitmap_codec: Codec::Brotli,
+            metadata_codec: Codec::Brotli,
+            metadata_start: 1_000_000,
+            parity_start: 0,
+            scan_order: DEFAULT_SCAN_ORDER,
+            context_coded_bitmap: false,
+        };
+        let _ = validate_decompression_info(&decompression_info, &header, 1_000_000, &limits);
+    }
+});
@@ -0,0 +1,30 @@
+#![no_main]
+//! Treats arbitrary bytes as an in-memory archive, and rebuilds every image in it without writing anything
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use spritezip::reader::ArchiveReader;
+use spritezip::validate::ArchiveLimits;
+
+fuzz_target!(|data: &[u8]| {
+    //small limits, so the fuzzer spends its time finding bugs rather than decoding huge images
+    let limits = ArchiveLimits {
+        max_image_dimension: 1024,
+        max_image_pixels: 256 * 256,
+        max_total_pixels: 1 << 20,
+        max_metadata_bytes: 1 << 20,
+        max_window_log: 22,
+    };
+
+    let archive = match ArchiveReader::new(Cursor::new(data), &limits) {
+        Ok(archive) => archive,
+        Err(_) => return,
+    };
+
+    for block_i in 0..archive.decompression_info.blocks.len() {
+        if let Ok(block_data) = archive.read_solid_block(block_i) {
+            let _ = archive.decode_solid_block(block_i, &block_data, false, |_, _| {});
+        }
+    }
+});
@@ -0,0 +1,428 @@
+//standard uses
+use std;
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+//non-standard use
+use image;
+use image::RgbaImage;
+use walkdir;
+
+use bitmap_coder::BitmapEncoder;
+use byte_counter::ByteCounter;
+use codec::{Codec, CodecSettings, new_compressor};
+use common::{FileTypeIterator, get_offset_to_other_image, try_get_pixel, hash_image};
+use common::{pretty_print_bytes, pretty_print_percent};
+use compress::{CompressionSettings, Cropper};
+use compress::{choose_reference_transform, pick_strategy, split_into_solid_blocks};
+
+//finding the greedy order compares every pair of images, so it is skipped for larger sets
+const MAX_GREEDY_ORDER_IMAGES : usize = 300;
+//number of neighbours listed in the worst neighbours table
+const NUM_WORST_NEIGHBOURS : usize = 10;
+//an alternative order/anchor is only recommended if it has at least this many fewer different pixels (percent)
+const RECOMMENDATION_THRESHOLD_PERCENT : u64 = 10;
+
+/// Where two images of different sizes are lined up with each other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anchor {
+    //what the compressor uses
+    BottomCenter,
+    BottomLeft,
+    Center,
+    TopCenter,
+    TopLeft,
+}
+
+impl Anchor {
+    pub const ALL : [Anchor; 5] = [Anchor::BottomCenter, Anchor::BottomLeft, Anchor::Center, Anchor::TopCenter, Anchor::TopLeft];
+
+    /// The offset to add to a pixel of image to get the matching pixel of reference_image
+    fn offset(&self, image : &RgbaImage, reference_image : &RgbaImage) -> (i64, i64)
+    {
+        let x_difference = reference_image.width() as i64 - image.width() as i64;
+        let y_difference = reference_image.height() as i64 - image.height() as i64;
+        match self {
+            Anchor::BottomCenter => get_offset_to_other_image(image, reference_image),
+            Anchor::BottomLeft => (0, y_difference),
+            Anchor::Center => (x_difference / 2, y_difference / 2),
+            Anchor::TopCenter => (x_difference / 2, 0),
+            Anchor::TopLeft => (0, 0),
+        }
+    }
+}
+
+/// How different two neighbouring images are
+#[derive(Debug, Clone)]
+pub struct NeighbourDiff {
+    pub previous_path : Option<String>,
+    pub path : String,
+    pub different_pixels : u64,
+    pub num_pixels : u64,
+    //area of the rectangle around the different pixels, which is the size of the bitmap
+    pub crop_area : u64,
+}
+
+/// Estimated size of the image data and bitmap streams with one codec
+#[derive(Debug, Clone)]
+pub struct CodecEstimate {
+    pub settings : CodecSettings,
+    pub image_bytes : u64,
+    pub bitmap_bytes : u64,
+}
+
+/// The results of analyzing a folder of images, see analyze_images
+#[derive(Debug, Clone, Default)]
+pub struct AnalysisReport {
+    pub num_images : usize,
+    pub num_duplicates : usize,
+    //width * height * 4 of every image which isn't a duplicate
+    pub raw_bytes : u64,
+    //the neighbours in the order the compressor would use (duplicates left out)
+    pub neighbours : Vec<NeighbourDiff>,
+    //total different pixels between neighbours for each ordering of the images (None if it was skipped)
+    pub orders : Vec<(&'static str, Option<u64>)>,
+    //total different pixels between neighbours (in the current order) when lined up at each anchor
+    pub anchors : Vec<(Anchor, u64)>,
+    //uncompressed size of the image data and bitmap streams
+    pub image_bytes : u64,
+    pub bitmap_bytes : u64,
+    pub estimates : Vec<CodecEstimate>,
+    //size of the bitmap streams with the context-modeling bitmap coder (--context-bitmap)
+    pub context_bitmap_bytes : u64,
+}
+
+/// Writes everything written to it into each of the writers
+struct TeeWriter<'a> {
+    writers : Vec<Box<dyn Write + 'a>>,
+}
+
+impl<'a> Write for TeeWriter<'a> {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize>
+    {
+        for writer in self.writers.iter_mut() {
+            writer.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        for writer in self.writers.iter_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// A fast setting for each codec, used to estimate which codec will do best on the full setting
+fn fast_codec_settings() -> Vec<CodecSettings>
+{
+    [(Codec::Brotli, 5), (Codec::Zstd, 9), (Codec::Xz, 3)].iter().map(|&(codec, quality)| CodecSettings {
+        quality,
+        ..codec.default_settings()
+    }).collect()
+}
+
+/// Compares image with reference_image lined up at the given anchor, returning the number of different pixels
+/// and the area of the rectangle around them. Pixels outside the reference count as different.
+fn diff_images(image : &RgbaImage, reference_image : &RgbaImage, anchor : Anchor) -> (u64, u64)
+{
+    let (x_offset_to_reference, y_offset_to_reference) = anchor.offset(image, reference_image);
+    let mut cropper = Cropper::new(image.dimensions());
+    let mut different_pixels = 0;
+    for (x, y, pixel) in image.enumerate_pixels()
+    {
+        if try_get_pixel((x as i64 + x_offset_to_reference, y as i64 + y_offset_to_reference), reference_image) != Some(*pixel) {
+            cropper.add_nonzero_pixel(x, y);
+            different_pixels += 1;
+        }
+    }
+
+    let crop_region = cropper.get_crop_region();
+    (different_pixels, crop_region.dimensions.0 as u64 * crop_region.dimensions.1 as u64)
+}
+
+/// Total different pixels between each image and the one before it (the first image is compared to the null image)
+fn order_different_pixels(images : &[&RgbaImage]) -> u64
+{
+    let null_image = RgbaImage::new(0, 0);
+    std::iter::once(&null_image).chain(images.iter().cloned()).zip(images.iter())
+        .map(|(reference_image, image)| diff_images(image, reference_image, Anchor::BottomCenter).0)
+        .sum()
+}
+
+/// Starting from the first image, repeatedly picks the image most similar to the last one picked
+fn greedy_order(images : &[&RgbaImage]) -> Vec<usize>
+{
+    let mut order = Vec::with_capacity(images.len());
+    let mut remaining : Vec<usize> = (0..images.len()).collect();
+    while !remaining.is_empty() {
+        let next_position = match order.last() {
+            None => 0,
+            Some(&last) => (0..remaining.len())
+                .min_by_key(|&position| diff_images(images[remaining[position]], images[last], Anchor::BottomCenter).0)
+                .unwrap(),
+        };
+        order.push(remaining.remove(next_position));
+    }
+    order
+}
+
+/// Runs the compressor's diffing (with the same solid blocks, duplicates and reference transforms) over the
+/// images, compressing the streams with every codec at a fast setting, and counting the bytes instead of writing them
+fn estimate_stream_sizes(image_entries : &[walkdir::DirEntry], settings : &CompressionSettings, report : &mut AnalysisReport) -> Result<(), String>
+{
+    let codec_settings = fast_codec_settings();
+    report.estimates = codec_settings.iter().map(|&settings| CodecEstimate { settings, image_bytes : 0, bitmap_bytes : 0 }).collect();
+
+    let mut image_hashes = HashSet::new();
+    for block_entries in split_into_solid_blocks(image_entries, &settings.block_limits)
+    {
+        let mut image_counters : Vec<ByteCounter<io::Sink>> = codec_settings.iter().map(|_| ByteCounter::new(io::sink(), None)).collect();
+        let mut bitmap_counters : Vec<ByteCounter<io::Sink>> = codec_settings.iter().map(|_| ByteCounter::new(io::sink(), None)).collect();
+        {
+            let mut image_compressor = ByteCounter::new(TeeWriter {
+                writers : image_counters.iter_mut().zip(codec_settings.iter()).map(|(counter, settings)| new_compressor(counter, settings)).collect::<Result<_, String>>()?,
+            }, None);
+            let mut bitmap_compressor = ByteCounter::new(TeeWriter {
+                writers : bitmap_counters.iter_mut().zip(codec_settings.iter()).map(|(counter, settings)| new_compressor(counter, settings)).collect::<Result<_, String>>()?,
+            }, None);
+            let mut context_bitmap_encoder = BitmapEncoder::new(ByteCounter::new(io::sink(), None));
+
+            let mut prev_image = RgbaImage::new(0, 0);
+            for ent in block_entries
+            {
+                let image = image::open(ent.path()).map_err(|e| format!("Unable to open {}: {}", ent.path().display(), e))?.to_rgba8();
+                if !image_hashes.insert(hash_image(&image)) {
+                    continue;
+                }
+
+                let (_, reference_image) = choose_reference_transform(&image, &prev_image);
+                let strategy = pick_strategy(settings, &image, &reference_image)?;
+                let mut bitmap = Vec::new();
+                let (crop_region, _) = strategy.encode(&image, &reference_image, None, settings.scan_order, &mut image_compressor, &mut bitmap);
+                bitmap_compressor.write_all(&bitmap).map_err(|e| format!("Unable to compress the bitmap of {}: {}", ent.path().display(), e))?;
+                context_bitmap_encoder.encode_image(&bitmap, image.dimensions(), &crop_region, strategy.scan_order(settings.scan_order, crop_region.dimensions), false)?;
+                prev_image = image;
+            }
+
+            report.image_bytes += image_compressor.bytes_written() as u64;
+            report.bitmap_bytes += bitmap_compressor.bytes_written() as u64;
+            report.context_bitmap_bytes += context_bitmap_encoder.finish()?.bytes_written() as u64;
+        }
+
+        for (estimate, (image_counter, bitmap_counter)) in report.estimates.iter_mut().zip(image_counters.iter().zip(bitmap_counters.iter())) {
+            estimate.image_bytes += image_counter.bytes_written() as u64;
+            estimate.bitmap_bytes += bitmap_counter.bytes_written() as u64;
+        }
+    }
+
+    Ok(())
+}
+
+/// Analyzes how well the images in input_folder will compress, without writing anything. This measures:
+/// - how different each image is from the one before it, in the order the compressor would use
+/// - the same total for other orderings of the images, and for other ways of lining up images of different sizes
+/// - the size of the compressed streams with each codec on a fast setting
+pub fn analyze_images(input_folder : &str, settings : &CompressionSettings) -> Result<AnalysisReport, String>
+{
+    let image_entries : Vec<walkdir::DirEntry> = FileTypeIterator::new(input_folder, "png").collect();
+    if image_entries.is_empty() {
+        return Err(format!("No .png images found in '{}'", input_folder));
+    }
+
+    let mut report = AnalysisReport {
+        num_images : image_entries.len(),
+        ..AnalysisReport::default()
+    };
+
+    //load every image once, leaving out duplicates (which cost nothing to store)
+    let mut image_hashes = HashSet::new();
+    let mut paths : Vec<String> = Vec::new();
+    let mut images : Vec<RgbaImage> = Vec::new();
+    for ent in &image_entries
+    {
+        let image = image::open(ent.path()).map_err(|e| format!("Unable to open {}: {}", ent.path().display(), e))?.to_rgba8();
+        if !image_hashes.insert(hash_image(&image)) {
+            report.num_duplicates += 1;
+            continue;
+        }
+        report.raw_bytes += image.width() as u64 * image.height() as u64 * 4;
+        paths.push(ent.path().strip_prefix(input_folder).unwrap().to_string_lossy().replace('\\', "/"));
+        images.push(image);
+    }
+
+    println!("Comparing neighbouring images...");
+    let null_image = RgbaImage::new(0, 0);
+    for i in 0..images.len()
+    {
+        let reference_image = if i == 0 { &null_image } else { &images[i - 1] };
+        let (different_pixels, crop_area) = diff_images(&images[i], reference_image, Anchor::BottomCenter);
+        report.neighbours.push(NeighbourDiff {
+            previous_path : if i == 0 { None } else { Some(paths[i - 1].clone()) },
+            path : paths[i].clone(),
+            different_pixels,
+            num_pixels : images[i].width() as u64 * images[i].height() as u64,
+            crop_area,
+        });
+    }
+
+    println!("Trying other image orders...");
+    let ordered_images = |order : &[usize]| -> Vec<&RgbaImage> { order.iter().map(|&i| &images[i]).collect() };
+    let current_order : Vec<usize> = (0..images.len()).collect();
+    let mut path_order = current_order.clone();
+    path_order.sort_by(|&a, &b| paths[a].cmp(&paths[b]));
+    //the file name first groups the same pose of different characters together
+    let file_name = |i : usize| paths[i].rsplit('/').next().unwrap().to_string();
+    let mut file_name_order = current_order.clone();
+    file_name_order.sort_by(|&a, &b| file_name(a).cmp(&file_name(b)).then(paths[a].cmp(&paths[b])));
+    let mut dimension_order = current_order.clone();
+    dimension_order.sort_by(|&a, &b| images[a].dimensions().cmp(&images[b].dimensions()).then(paths[a].cmp(&paths[b])));
+
+    report.orders.push(("current (folder order)", Some(report.neighbours.iter().map(|neighbour| neighbour.different_pixels).sum())));
+    report.orders.push(("sorted by path", Some(order_different_pixels(&ordered_images(&path_order)))));
+    report.orders.push(("sorted by file name", Some(order_different_pixels(&ordered_images(&file_name_order)))));
+    report.orders.push(("sorted by dimensions", Some(order_different_pixels(&ordered_images(&dimension_order)))));
+    report.orders.push(("most similar next (greedy)", if images.len() <= MAX_GREEDY_ORDER_IMAGES {
+        Some(order_different_pixels(&ordered_images(&greedy_order(&ordered_images(&current_order)))))
+    } else {
+        None
+    }));
+
+    println!("Trying other alignment anchors...");
+    for anchor in Anchor::ALL.iter() {
+        let different_pixels = std::iter::once(&null_image).chain(images.iter()).zip(images.iter())
+            .map(|(reference_image, image)| diff_images(image, reference_image, *anchor).0)
+            .sum();
+        report.anchors.push((*anchor, different_pixels));
+    }
+
+    println!("Estimating compressed size...");
+    estimate_stream_sizes(&image_entries, settings, &mut report)?;
+
+    Ok(report)
+}
+
+impl AnalysisReport {
+    /// The neighbours with the highest proportion of different pixels, worst first
+    pub fn worst_neighbours(&self) -> Vec<&NeighbourDiff>
+    {
+        //the first image is always 100% different, so it isn't interesting
+        let mut neighbours : Vec<&NeighbourDiff> = self.neighbours.iter().skip(1).collect();
+        neighbours.sort_by(|a, b| (b.different_pixels * a.num_pixels.max(1)).cmp(&(a.different_pixels * b.num_pixels.max(1))));
+        neighbours.truncate(NUM_WORST_NEIGHBOURS);
+        neighbours
+    }
+
+    /// Suggestions for getting a smaller archive, based on the measurements
+    pub fn recommendations(&self) -> Vec<String>
+    {
+        let mut recommendations = Vec::new();
+        let is_much_better = |different_pixels : u64, current : u64| different_pixels * 100 < current * (100 - RECOMMENDATION_THRESHOLD_PERCENT);
+
+        let current_order = self.orders[0].1.unwrap();
+        if let Some(&(name, Some(different_pixels))) = self.orders.iter().filter(|(_, total)| total.is_some()).min_by_key(|(_, total)| *total) {
+            if is_much_better(different_pixels, current_order) {
+                recommendations.push(format!("Rename or regroup the images so they are {} - there would be {} fewer different pixels between neighbours",
+                    name, pretty_print_percent(current_order - different_pixels, current_order).trim()));
+            }
+        }
+
+        let bottom_center = self.anchors[0].1;
+        if let Some(&(anchor, different_pixels)) = self.anchors.iter().min_by_key(|(_, total)| *total) {
+            if is_much_better(different_pixels, bottom_center) {
+                recommendations.push(format!("The sprites look {:?} aligned rather than bottom-center aligned - pad them so they are bottom-center aligned ({} fewer different pixels)",
+                    anchor, pretty_print_percent(bottom_center - different_pixels, bottom_center).trim()));
+            }
+        }
+
+        let best_image_codec = self.estimates.iter().min_by_key(|estimate| estimate.image_bytes).unwrap().settings.codec;
+        let best_bitmap_codec = self.estimates.iter().min_by_key(|estimate| estimate.bitmap_bytes).unwrap().settings.codec;
+        recommendations.push(if best_image_codec == best_bitmap_codec {
+            format!("Use '--codec {}'", best_image_codec.name())
+        } else {
+            format!("Use '--image-codec {} --bitmap-codec {}'", best_image_codec.name(), best_bitmap_codec.name())
+        });
+
+        let best_bitmap_bytes = self.estimates.iter().map(|estimate| estimate.bitmap_bytes).min().unwrap();
+        if self.context_bitmap_bytes < best_bitmap_bytes {
+            recommendations.push(format!("Use '--context-bitmap' - the bitmaps are {} smaller than with {}",
+                pretty_print_percent(best_bitmap_bytes - self.context_bitmap_bytes, best_bitmap_bytes).trim(), best_bitmap_codec.name()));
+        }
+
+        recommendations
+    }
+
+    pub fn print(&self)
+    {
+        println!("\n ------------ Analysis ------------");
+        println!("{} image(s), {} of them duplicates, {} of raw image data", self.num_images, self.num_duplicates, pretty_print_bytes(self.raw_bytes as f64));
+        println!("Streams before compression: image data {}, bitmap {}", pretty_print_bytes(self.image_bytes as f64), pretty_print_bytes(self.bitmap_bytes as f64));
+
+        println!("\nWorst neighbours (current order):");
+        for neighbour in self.worst_neighbours() {
+            println!("{} different ({} px, diff rectangle {} px): {} -> {}",
+                pretty_print_percent(neighbour.different_pixels, neighbour.num_pixels),
+                neighbour.different_pixels, neighbour.crop_area,
+                neighbour.previous_path.as_deref().unwrap_or(""), neighbour.path);
+        }
+
+        println!("\nDifferent pixels between neighbours for each image order:");
+        for (name, different_pixels) in &self.orders {
+            match different_pixels {
+                Some(different_pixels) => println!("{:>12} {}", different_pixels, name),
+                None => println!("{:>12} {} (skipped, more than {} images)", "-", name, MAX_GREEDY_ORDER_IMAGES),
+            }
+        }
+
+        println!("\nDifferent pixels between neighbours for each alignment anchor (the compressor uses BottomCenter):");
+        for (anchor, different_pixels) in &self.anchors {
+            println!("{:>12} {:?}", different_pixels, anchor);
+        }
+
+        println!("\nEstimated size with a fast codec setting (the default settings will be smaller; metadata not included):");
+        for estimate in &self.estimates {
+            println!("{:>6} quality {:>2}: {} (image data {}, bitmap {})",
+                estimate.settings.codec.name(), estimate.settings.quality,
+                pretty_print_bytes((estimate.image_bytes + estimate.bitmap_bytes) as f64),
+                pretty_print_bytes(estimate.image_bytes as f64),
+                pretty_print_bytes(estimate.bitmap_bytes as f64));
+        }
+        println!("Bitmap with '--context-bitmap': {}", pretty_print_bytes(self.context_bitmap_bytes as f64));
+
+        println!("\nRecommendations:");
+        for recommendation in self.recommendations() {
+            println!("- {}", recommendation);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::{diff_images, greedy_order, Anchor};
+
+    #[test]
+    fn anchors_line_up_different_sizes()
+    {
+        //a 2x2 image which matches the bottom-left corner of a 4x4 image
+        let large = RgbaImage::from_fn(4, 4, |x, y| Rgba([x as u8, y as u8, 0, 255]));
+        let small = RgbaImage::from_fn(2, 2, |x, y| Rgba([x as u8, y as u8 + 2, 0, 255]));
+
+        assert_eq!(diff_images(&small, &large, Anchor::BottomLeft), (0, 0));
+        assert_eq!(diff_images(&small, &large, Anchor::TopLeft), (4, 4));
+        assert_eq!(diff_images(&small, &large, Anchor::BottomCenter).0, 4);
+        //pixels outside the reference are always different
+        assert_eq!(diff_images(&large, &small, Anchor::BottomLeft), (12, 16));
+    }
+
+    #[test]
+    fn greedy_order_follows_most_similar_image()
+    {
+        let image = |value : u8| RgbaImage::from_fn(3, 1, |x, _| Rgba([if x < value as u32 { 1 } else { 0 }, 0, 0, 255]));
+        let (zero, one, two, three) = (image(0), image(1), image(2), image(3));
+        assert_eq!(greedy_order(&[&zero, &three, &one, &two]), vec![0, 2, 3, 1]);
+    }
+}
@@ -0,0 +1,427 @@
+//standard uses
+use std::io::{Read, Write};
+
+use codec::{CodecSettings, new_compressor, new_decompressor};
+use common::ArchiveHeader;
+use compress::CropRegion;
+use scan_order::ScanIterator;
+
+// A dedicated coder for the difference bitmap, used instead of the bitmap codec if the archive header says so.
+// Each bit is coded with an adaptive binary range coder (the one from LZMA), using a probability picked by its
+// context, like JBIG2: the left, above and above-left bits, and the bit at the same place in the previous image's
+// bitmap. The scan order may visit a neighbour after the bit itself, so neighbours which haven't been coded yet are
+// a separate context value. Neighbours outside the diff rectangle are unchanged, so they count as 0.
+// Bidirectional images (B-frames) also have 2 in their bitmaps, for pixels taken from the next image. For those
+// images, every pixel which isn't stored codes a second bit, for which image it comes from, in the context of the
+// left and above values.
+
+//probabilities (of a 0 bit) are 11 bit fixed point
+const PROBABILITY_BITS : u32 = 11;
+const PROBABILITY_ONE : u32 = 1 << PROBABILITY_BITS;
+//how quickly the probabilities adapt - each bit moves its probability 1/32 of the way towards what was seen
+const ADAPTATION_SHIFT : u32 = 5;
+//the range is renormalized (shifted out a byte at a time) whenever it drops below this
+const RANGE_TOP : u32 = 1 << 24;
+
+//neighbours which haven't been coded yet
+const NOT_CODED : u8 = 3;
+//left, above and above-left are each not stored (0 or 2), stored (1) or NOT_CODED, and the previous bitmap is 0 or 1
+const NUM_CONTEXTS : usize = 3 * 3 * 3 * 2;
+//left and above are each 0, 1, 2 or NOT_CODED
+const NUM_SOURCE_CONTEXTS : usize = 4 * 4;
+//bitmap value of B-frame pixels taken from the next image
+const FROM_NEXT_IMAGE : u8 = 2;
+
+struct RangeEncoder<W : Write> {
+    writer : W,
+    low : u64,
+    range : u32,
+    //the last byte shifted out, which is held back (along with pending 0xFF bytes) in case a carry changes it
+    cache : u8,
+    cache_size : u64,
+}
+
+impl<W : Write> RangeEncoder<W> {
+    fn new(writer : W) -> RangeEncoder<W>
+    {
+        RangeEncoder { writer, low : 0, range : 0xFFFF_FFFF, cache : 0, cache_size : 1 }
+    }
+
+    fn encode_bit(&mut self, probability : &mut u16, bit : u8) -> Result<(), String>
+    {
+        let bound = (self.range >> PROBABILITY_BITS) * *probability as u32;
+        if bit == 0 {
+            self.range = bound;
+            *probability += ((PROBABILITY_ONE - *probability as u32) >> ADAPTATION_SHIFT) as u16;
+        }
+        else {
+            self.low += bound as u64;
+            self.range -= bound;
+            *probability -= *probability >> ADAPTATION_SHIFT;
+        }
+
+        while self.range < RANGE_TOP {
+            self.range <<= 8;
+            self.shift_low()?;
+        }
+        Ok(())
+    }
+
+    fn shift_low(&mut self) -> Result<(), String>
+    {
+        if self.low < 0xFF00_0000 || self.low > 0xFFFF_FFFF {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            while self.cache_size > 0 {
+                self.writer.write_all(&[byte.wrapping_add(carry)]).map_err(|e| format!("Unable to write bitmap: {}", e))?;
+                byte = 0xFF;
+                self.cache_size -= 1;
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FF_FFFF) << 8;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W, String>
+    {
+        for _ in 0..5 {
+            self.shift_low()?;
+        }
+        Ok(self.writer)
+    }
+}
+
+struct RangeDecoder<'a> {
+    input : &'a [u8],
+    range : u32,
+    code : u32,
+}
+
+impl<'a> RangeDecoder<'a> {
+    fn new(input : &'a [u8]) -> Result<RangeDecoder<'a>, String>
+    {
+        let mut decoder = RangeDecoder { input, range : 0xFFFF_FFFF, code : 0 };
+        //the encoder always starts with a zero byte (the initial cache), so this reads 4 bytes of code
+        for _ in 0..5 {
+            decoder.code = (decoder.code << 8) | decoder.next_byte()? as u32;
+        }
+        Ok(decoder)
+    }
+
+    fn next_byte(&mut self) -> Result<u8, String>
+    {
+        match self.input.split_first() {
+            Some((&byte, rest)) => {
+                self.input = rest;
+                Ok(byte)
+            },
+            None => Err("Bitmap stream ended early".to_string()),
+        }
+    }
+
+    fn decode_bit(&mut self, probability : &mut u16) -> Result<u8, String>
+    {
+        let bound = (self.range >> PROBABILITY_BITS) * *probability as u32;
+        let bit = if self.code < bound {
+            self.range = bound;
+            *probability += ((PROBABILITY_ONE - *probability as u32) >> ADAPTATION_SHIFT) as u16;
+            0
+        }
+        else {
+            self.code -= bound;
+            self.range -= bound;
+            *probability -= *probability >> ADAPTATION_SHIFT;
+            1
+        };
+
+        while self.range < RANGE_TOP {
+            self.range <<= 8;
+            self.code = (self.code << 8) | self.next_byte()? as u32;
+        }
+        Ok(bit)
+    }
+}
+
+/// The context model, which the encoder and decoder update identically
+struct BitmapModel {
+    //probabilities of a pixel not being stored
+    probabilities : [u16; NUM_CONTEXTS],
+    //probabilities of a B-frame pixel which isn't stored coming from the previous image rather than the next one
+    source_probabilities : [u16; NUM_SOURCE_CONTEXTS],
+    //size of the previous image, and which of its pixels were changed (1 where its bitmap was 1, 0 elsewhere)
+    prev_image_size : (u32, u32),
+    prev_changed : Vec<u8>,
+}
+
+impl BitmapModel {
+    fn new() -> BitmapModel
+    {
+        BitmapModel {
+            probabilities : [(PROBABILITY_ONE / 2) as u16; NUM_CONTEXTS],
+            source_probabilities : [(PROBABILITY_ONE / 2) as u16; NUM_SOURCE_CONTEXTS],
+            prev_image_size : (0, 0),
+            prev_changed : Vec::new(),
+        }
+    }
+
+    /// Visits the bitmap of one image in scan order. code_value is given the index of each value (in scan order),
+    /// the probability for whether it is stored, and for bidirectional images the probability for where it comes
+    /// from otherwise. It codes the value, returning it.
+    fn code_image<F>(&mut self, image_size : (u32, u32), diff_region : &CropRegion, points : ScanIterator, bidirectional : bool, mut code_value : F) -> Result<(), String>
+    where F : FnMut(usize, &mut u16, Option<&mut u16>) -> Result<u8, String>
+    {
+        //whether each value was stored: 0 (not stored), 1 (stored) or 2 (NOT_CODED)
+        let stored_context = |value : u8| match value {
+            1 => 1,
+            NOT_CODED => 2,
+            _ => 0,
+        };
+
+        let (diff_width, diff_height) = (diff_region.dimensions.0 as usize, diff_region.dimensions.1 as usize);
+        let mut bitmap = vec![NOT_CODED; diff_width * diff_height];
+
+        //the previous image is lined up bottom-center, the same way the compressor lines up images
+        let prev_x_offset = (self.prev_image_size.0 as i64 - image_size.0 as i64) / 2 + diff_region.top_left.0 as i64;
+        let prev_y_offset = self.prev_image_size.1 as i64 - image_size.1 as i64 + diff_region.top_left.1 as i64;
+
+        for (i, (x, y)) in points.enumerate() {
+            let (x, y) = (x as usize, y as usize);
+            let left = if x == 0 { 0 } else { bitmap[y * diff_width + x - 1] };
+            let above = if y == 0 { 0 } else { bitmap[(y - 1) * diff_width + x] };
+            let above_left = if x == 0 || y == 0 { 0 } else { bitmap[(y - 1) * diff_width + x - 1] };
+
+            let (prev_x, prev_y) = (x as i64 + prev_x_offset, y as i64 + prev_y_offset);
+            let prev = if prev_x < 0 || prev_y < 0 || prev_x >= self.prev_image_size.0 as i64 || prev_y >= self.prev_image_size.1 as i64 {
+                0
+            }
+            else {
+                self.prev_changed[prev_y as usize * self.prev_image_size.0 as usize + prev_x as usize]
+            };
+
+            let context = ((stored_context(left) * 3 + stored_context(above)) * 3 + stored_context(above_left)) * 2 + prev as usize;
+            let source_probability = if bidirectional { Some(&mut self.source_probabilities[left as usize * 4 + above as usize]) } else { None };
+            bitmap[y * diff_width + x] = code_value(i, &mut self.probabilities[context], source_probability)?;
+        }
+
+        //every pixel of the diff rectangle has now been coded
+        self.prev_image_size = image_size;
+        self.prev_changed = vec![0; image_size.0 as usize * image_size.1 as usize];
+        for y in 0..diff_height {
+            let start = (y + diff_region.top_left.1 as usize) * image_size.0 as usize + diff_region.top_left.0 as usize;
+            for (changed, &value) in self.prev_changed[start..start + diff_width].iter_mut().zip(&bitmap[y * diff_width..(y + 1) * diff_width]) {
+                *changed = (value == 1) as u8;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Codes the bitmaps of the images of one solid block, in order
+pub struct BitmapEncoder<W : Write> {
+    model : BitmapModel,
+    encoder : RangeEncoder<W>,
+}
+
+impl<W : Write> BitmapEncoder<W> {
+    pub fn new(writer : W) -> BitmapEncoder<W>
+    {
+        BitmapEncoder { model : BitmapModel::new(), encoder : RangeEncoder::new(writer) }
+    }
+
+    /// Codes the bitmap of one image - a 0 or 1 for every pixel of the diff rectangle, in the order of points (or
+    /// 0, 1 or 2 for bidirectional images)
+    pub fn encode_image(&mut self, bitmap : &[u8], image_size : (u32, u32), diff_region : &CropRegion, points : ScanIterator, bidirectional : bool) -> Result<(), String>
+    {
+        if bitmap.len() != diff_region.dimensions.0 as usize * diff_region.dimensions.1 as usize {
+            return Err(format!("Bitmap has {} values, but the diff rectangle is {}x{}", bitmap.len(), diff_region.dimensions.0, diff_region.dimensions.1));
+        }
+
+        let encoder = &mut self.encoder;
+        self.model.code_image(image_size, diff_region, points, bidirectional, |i, stored_probability, source_probability| {
+            let value = bitmap[i];
+            let max_value = if bidirectional { FROM_NEXT_IMAGE } else { 1 };
+            if value > max_value {
+                return Err(format!("Bitmap value {} can't be context coded (only 0 to {} can)", value, max_value));
+            }
+            encoder.encode_bit(stored_probability, (value == 1) as u8)?;
+            if let (Some(source_probability), false) = (source_probability, value == 1) {
+                encoder.encode_bit(source_probability, (value == FROM_NEXT_IMAGE) as u8)?;
+            }
+            Ok(value)
+        })
+    }
+
+    /// Flushes the last bytes of the coder, returning the writer
+    pub fn finish(self) -> Result<W, String>
+    {
+        self.encoder.finish()
+    }
+}
+
+/// Decodes the bitmaps written by BitmapEncoder
+pub struct BitmapDecoder<'a> {
+    model : BitmapModel,
+    decoder : RangeDecoder<'a>,
+}
+
+impl<'a> BitmapDecoder<'a> {
+    pub fn new(input : &'a [u8]) -> Result<BitmapDecoder<'a>, String>
+    {
+        Ok(BitmapDecoder { model : BitmapModel::new(), decoder : RangeDecoder::new(input)? })
+    }
+
+    pub fn decode_image(&mut self, image_size : (u32, u32), diff_region : &CropRegion, points : ScanIterator, bidirectional : bool) -> Result<Vec<u8>, String>
+    {
+        let mut bitmap = vec![0u8; diff_region.dimensions.0 as usize * diff_region.dimensions.1 as usize];
+        let decoder = &mut self.decoder;
+        self.model.code_image(image_size, diff_region, points, bidirectional, |i, stored_probability, source_probability| {
+            bitmap[i] = match (decoder.decode_bit(stored_probability)?, source_probability) {
+                (1, _) => 1,
+                (_, Some(source_probability)) => decoder.decode_bit(source_probability)? * FROM_NEXT_IMAGE,
+                (_, None) => 0,
+            };
+            Ok(bitmap[i])
+        })?;
+        Ok(bitmap)
+    }
+}
+
+/// The bitmap stream of one solid block, as it is written - either compressed with the bitmap codec, or
+/// context coded
+pub enum BitmapStreamWriter<'a> {
+    Codec(Box<dyn Write + 'a>),
+    Context(BitmapEncoder<Box<dyn Write + 'a>>),
+}
+
+impl<'a> BitmapStreamWriter<'a> {
+    pub fn new<W : Write + 'a>(writer : W, codec_settings : &CodecSettings, context_coded : bool) -> Result<BitmapStreamWriter<'a>, String>
+    {
+        if context_coded {
+            Ok(BitmapStreamWriter::Context(BitmapEncoder::new(Box::new(writer))))
+        }
+        else {
+            Ok(BitmapStreamWriter::Codec(new_compressor(writer, codec_settings)?))
+        }
+    }
+
+    /// Adds the bitmap of one image, as written by its encoding strategy (see DiffStrategy)
+    pub fn write_image(&mut self, bitmap : &[u8], image_size : (u32, u32), diff_region : &CropRegion, points : ScanIterator, bidirectional : bool) -> Result<(), String>
+    {
+        match self {
+            BitmapStreamWriter::Codec(compressor) => compressor.write_all(bitmap).map_err(|e| format!("Unable to write bitmap: {}", e)),
+            BitmapStreamWriter::Context(encoder) => encoder.encode_image(bitmap, image_size, diff_region, points, bidirectional),
+        }
+    }
+
+    /// Writes out everything still buffered
+    pub fn finish(self) -> Result<(), String>
+    {
+        match self {
+            //the codecs finish their streams when dropped
+            BitmapStreamWriter::Codec(_) => Ok(()),
+            BitmapStreamWriter::Context(encoder) => encoder.finish().map(|_| ()),
+        }
+    }
+}
+
+/// Reads the bitmap stream of one solid block, one image at a time
+pub enum BitmapStreamReader<'a> {
+    Codec(Box<dyn Read + 'a>),
+    Context(BitmapDecoder<'a>),
+}
+
+impl<'a> BitmapStreamReader<'a> {
+    pub fn new(bitmap_data : &'a [u8], header : &ArchiveHeader, max_window_log : u32) -> Result<BitmapStreamReader<'a>, String>
+    {
+        if header.context_coded_bitmap {
+            Ok(BitmapStreamReader::Context(BitmapDecoder::new(bitmap_data)?))
+        }
+        else {
+            Ok(BitmapStreamReader::Codec(new_decompressor(bitmap_data, header.bitmap_codec, max_window_log)?))
+        }
+    }
+
+    /// The bitmap of the next image, with one value for each pixel of its diff rectangle
+    pub fn read_image(&mut self, image_size : (u32, u32), diff_region : &CropRegion, points : ScanIterator, bidirectional : bool) -> Result<Vec<u8>, String>
+    {
+        match self {
+            BitmapStreamReader::Codec(decompressor) => {
+                let mut bitmap = vec![0u8; diff_region.dimensions.0 as usize * diff_region.dimensions.1 as usize];
+                decompressor.read_exact(&mut bitmap).map_err(|e| format!("Unable to read bitmap: {}", e))?;
+                Ok(bitmap)
+            },
+            BitmapStreamReader::Context(decoder) => decoder.decode_image(image_size, diff_region, points, bidirectional),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use proptest::collection::vec;
+
+    use compress::CropRegion;
+    use scan_order::ScanOrder;
+    use super::{BitmapDecoder, BitmapEncoder};
+
+    //an image size, a diff rectangle (x, y, width, height) inside it, a bitmap for the rectangle, and whether it
+    //is the bitmap of a bidirectional image
+    type ImageBitmap = ((u32, u32), (u32, u32, u32, u32), Vec<u8>, bool);
+
+    fn image_bitmap() -> impl Strategy<Value = ImageBitmap>
+    {
+        (1u32..40, 1u32..40).prop_flat_map(|(width, height)| {
+            (Just((width, height)), 0..width, 0..height).prop_flat_map(|(size, x, y)| {
+                (Just(size), Just(x), Just(y), 0..=size.0 - x, 0..=size.1 - y)
+            })
+        }).prop_flat_map(|(size, x, y, diff_width, diff_height)| {
+            any::<bool>().prop_flat_map(move |bidirectional| {
+                //mostly runs of the same value, like real bitmaps
+                let max_value : u8 = if bidirectional { 2 } else { 1 };
+                let value = prop_oneof![4 => Just(0u8), 1 => 1..=max_value];
+                (Just(size), Just((x, y, diff_width, diff_height)), vec(value, (diff_width * diff_height) as usize), Just(bidirectional))
+            })
+        })
+    }
+
+    fn scan_order() -> impl Strategy<Value = ScanOrder>
+    {
+        prop_oneof![Just(ScanOrder::Blocks(50)), Just(ScanOrder::Blocks(3)), Just(ScanOrder::Snake), Just(ScanOrder::Hilbert)]
+    }
+
+    proptest! {
+        #[test]
+        fn bitmaps_round_trip(images in vec(image_bitmap(), 1..6), scan_order in scan_order())
+        {
+            let region = |&(x, y, width, height) : &(u32, u32, u32, u32)| CropRegion { top_left : (x, y), dimensions : (width, height) };
+
+            let mut encoder = BitmapEncoder::new(Vec::new());
+            for (size, diff, bitmap, bidirectional) in &images {
+                encoder.encode_image(bitmap, *size, &region(diff), scan_order.iter((diff.2, diff.3)), *bidirectional).unwrap();
+            }
+            let coded = encoder.finish().unwrap();
+
+            let mut decoder = BitmapDecoder::new(&coded).unwrap();
+            for (size, diff, bitmap, bidirectional) in &images {
+                prop_assert_eq!(&decoder.decode_image(*size, &region(diff), scan_order.iter((diff.2, diff.3)), *bidirectional).unwrap(), bitmap);
+            }
+        }
+    }
+
+    #[test]
+    fn repeated_bitmaps_code_small()
+    {
+        //a 64x64 circle, five times over - the previous bitmap context should make the repeats almost free
+        let bitmap : Vec<u8> = (0..64 * 64).map(|i| { let (x, y) = (i % 64 - 32, i / 64 - 32); (x * x + y * y < 400) as u8 }).collect();
+        let region = CropRegion { top_left : (0, 0), dimensions : (64, 64) };
+
+        let mut encoder = BitmapEncoder::new(Vec::new());
+        for _ in 0..5 {
+            encoder.encode_image(&bitmap, (64, 64), &region, ScanOrder::Blocks(50).iter((64, 64)), false).unwrap();
+        }
+        let coded = encoder.finish().unwrap();
+        assert!(coded.len() < 200, "5 copies of a 4096 pixel bitmap coded to {} bytes", coded.len());
+    }
+}
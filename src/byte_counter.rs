@@ -1,22 +1,26 @@
-/// From https://stackoverflow.com/questions/42187591/how-to-keep-track-of-how-many-bytes-written-when-using-stdiowrite
+// From https://stackoverflow.com/questions/42187591/how-to-keep-track-of-how-many-bytes-written-when-using-stdiowrite
 //let mut compressed_bitmap_wrapper = ByteCounter::new(&mut compressed_bitmap_data_vector , 1_000_000);
 //let mut bitmap_compressor = brotli::CompressorWriter::new(compressed_bitmap_wrapper, BROTLI_BUFFER_SIZE, brotli_quality, brotli_window);
 
-use std::io::{self, Write, Seek, SeekFrom};
+use std;
+use std::io::Write;
+
+use common::pretty_print_bytes;
 
 pub struct ByteCounter<W> {
     inner: W,
     count: usize,
     bytes_seen: usize,
-    print_size: usize,
+    //print the total every time this many bytes have been written (None to never print)
+    print_size: Option<usize>,
 }
 
 impl<W> ByteCounter<W>
     where W: Write
 {
-    pub fn new(inner: W, print_size : usize) -> Self {
+    pub fn new(inner: W, print_size : Option<usize>) -> Self {
         ByteCounter {
-            inner: inner,
+            inner,
             count: 0,
             bytes_seen: 0,
             print_size,
@@ -39,9 +43,11 @@ impl<W> Write for ByteCounter<W>
         let res = self.inner.write(buf);
         if let Ok(size) = res {
             self.bytes_seen += size;
-            if self.bytes_seen > self.print_size {
-                self.bytes_seen = 0;
-                println!("Mem: {}", pretty_print_bytes(self.count as f64))
+            if let Some(print_size) = self.print_size {
+                if self.bytes_seen > print_size {
+                    self.bytes_seen = 0;
+                    println!("Mem: {}", pretty_print_bytes(self.count as f64))
+                }
             }
 
             self.count += size
@@ -0,0 +1,178 @@
+//standard uses
+use std::io::{Read, Write};
+
+//nonstandard use
+use brotli;
+use xz2;
+use zstd;
+
+use common::BROTLI_BUFFER_SIZE;
+
+/// The general purpose compressors which can be used for each stream in the archive.
+/// The id of the codec used for each stream is stored in the archive header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Brotli,
+    Zstd,
+    Xz,
+}
+
+impl Codec {
+    pub fn id(&self) -> u8
+    {
+        match self {
+            Codec::Brotli => 0,
+            Codec::Zstd => 1,
+            Codec::Xz => 2,
+        }
+    }
+
+    pub fn from_id(id : u8) -> Result<Codec, String>
+    {
+        match id {
+            0 => Ok(Codec::Brotli),
+            1 => Ok(Codec::Zstd),
+            2 => Ok(Codec::Xz),
+            _ => Err(format!("Unknown codec id {}", id)),
+        }
+    }
+
+    pub fn from_name(name : &str) -> Result<Codec, String>
+    {
+        match name {
+            "brotli" => Ok(Codec::Brotli),
+            "zstd" => Ok(Codec::Zstd),
+            "xz" | "lzma" => Ok(Codec::Xz),
+            _ => Err(format!("Unknown codec '{}' (expected brotli, zstd or xz)", name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str
+    {
+        match self {
+            Codec::Brotli => "brotli",
+            Codec::Zstd => "zstd",
+            Codec::Xz => "xz",
+        }
+    }
+
+    /// The slowest/best setting for each codec, and the largest window which is still reasonable for memory usage
+    pub fn default_settings(&self) -> CodecSettings
+    {
+        let (quality, window) = match self {
+            Codec::Brotli => (11, 24),
+            Codec::Zstd => (19, 27),
+            Codec::Xz => (9, 26),
+        };
+
+        CodecSettings {
+            codec : *self,
+            quality,
+            window,
+            buffer_size : BROTLI_BUFFER_SIZE,
+        }
+    }
+}
+
+/// Settings used when compressing a stream. Only the codec is needed for decompression.
+/// quality: brotli 0-11, zstd 1-22, xz 0-9
+/// window: log2 of the window/dictionary size (brotli 10-24, zstd 10-31, xz 12-30)
+/// buffer_size: size of the internal brotli buffer (ignored by the other codecs)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodecSettings {
+    pub codec : Codec,
+    pub quality : u32,
+    pub window : u32,
+    pub buffer_size : usize,
+}
+
+impl CodecSettings {
+    /// Checks that the quality and window are in range for the codec (see above)
+    pub fn validate(&self) -> Result<(), String>
+    {
+        let (quality_range, window_range) = match self.codec {
+            Codec::Brotli => (0..=11, 10..=24),
+            Codec::Zstd => (1..=22, 10..=31),
+            Codec::Xz => (0..=9, 12..=30),
+        };
+        if !quality_range.contains(&self.quality) {
+            return Err(format!("Quality {} is out of range for {} ({} to {})", self.quality, self.codec.name(), quality_range.start(), quality_range.end()));
+        }
+        if !window_range.contains(&self.window) {
+            return Err(format!("Window {} is out of range for {} ({} to {})", self.window, self.codec.name(), window_range.start(), window_range.end()));
+        }
+        if self.buffer_size == 0 {
+            return Err("Buffer size must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for CodecSettings {
+    fn default() -> CodecSettings
+    {
+        Codec::Brotli.default_settings()
+    }
+}
+
+/// Wraps a writer with a compressor for the given codec. Like the brotli compressor,
+/// the stream is finished when the returned writer is dropped.
+pub fn new_compressor<'a, W : Write + 'a>(writer : W, settings : &CodecSettings) -> Result<Box<dyn Write + 'a>, String>
+{
+    settings.validate()?;
+    match settings.codec {
+        Codec::Brotli => Ok(Box::new(brotli::CompressorWriter::new(writer, settings.buffer_size, settings.quality, settings.window))),
+        Codec::Zstd => {
+            let mut encoder = zstd::Encoder::new(writer, settings.quality as i32).map_err(|e| format!("Unable to create zstd compressor: {}", e))?;
+            //long mode lets zstd find matches in images far back in the stream
+            encoder.long_distance_matching(true).map_err(|e| format!("Unable to enable zstd long mode: {}", e))?;
+            encoder.window_log(settings.window).map_err(|e| format!("Invalid zstd window size {}: {}", settings.window, e))?;
+            Ok(Box::new(encoder.auto_finish()))
+        },
+        Codec::Xz => {
+            let mut lzma_options = xz2::stream::LzmaOptions::new_preset(settings.quality).map_err(|e| format!("Invalid xz quality {}: {}", settings.quality, e))?;
+            lzma_options.dict_size(1 << settings.window);
+            let mut filters = xz2::stream::Filters::new();
+            filters.lzma2(&lzma_options);
+            let stream = xz2::stream::Stream::new_stream_encoder(&filters, xz2::stream::Check::Crc64).map_err(|e| format!("Unable to create xz compressor: {}", e))?;
+            Ok(Box::new(xz2::write::XzEncoder::new_stream(writer, stream)))
+        },
+    }
+}
+
+/// Wraps a reader with a decompressor for the given codec.
+/// max_window_log limits the memory the zstd/xz decompressors will allocate for their window (brotli's is always small).
+pub fn new_decompressor<'a, R : Read + 'a>(reader : R, codec : Codec, max_window_log : u32) -> Result<Box<dyn Read + 'a>, String>
+{
+    match codec {
+        Codec::Brotli => Ok(Box::new(brotli::Decompressor::new(reader, BROTLI_BUFFER_SIZE))),
+        Codec::Zstd => {
+            let mut decoder = zstd::Decoder::new(reader).map_err(|e| format!("Unable to create zstd decompressor: {}", e))?;
+            decoder.window_log_max(max_window_log).map_err(|e| format!("Invalid zstd window limit: {}", e))?;
+            Ok(Box::new(decoder))
+        },
+        Codec::Xz => {
+            //the lzma2 decoder needs the dictionary plus a little extra for its own state
+            let memory_limit = (1u64 << max_window_log) + (1 << 24);
+            let stream = xz2::stream::Stream::new_stream_decoder(memory_limit, 0).map_err(|e| format!("Unable to create xz decompressor: {}", e))?;
+            Ok(Box::new(xz2::read::XzDecoder::new_stream(reader, stream)))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Codec, CodecSettings, new_compressor};
+
+    #[test]
+    fn out_of_range_settings_are_rejected()
+    {
+        for codec in [Codec::Brotli, Codec::Zstd, Codec::Xz].iter() {
+            let defaults = codec.default_settings();
+            assert!(new_compressor(Vec::new(), &defaults).is_ok());
+            for settings in [CodecSettings { quality : 100, ..defaults }, CodecSettings { window : 40, ..defaults }, CodecSettings { window : 5, ..defaults }].iter() {
+                assert!(new_compressor(Vec::new(), settings).is_err(), "{:?} was accepted", settings);
+            }
+        }
+    }
+}
@@ -4,17 +4,162 @@ use image::RgbaImage;
 use walkdir;
 
 use std;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::fs;
+use std::env;
 use std::io::BufReader;
 use std::io::{Read, Write};
-use brotli;
 use number_prefix::NumberPrefix;
+use sha2::{Digest, Sha256};
 
-pub const FILE_FORMAT_HEADER_LENGTH: usize = 8;
+use codec::{CodecSettings, new_compressor};
+use codec::Codec;
+use scan_order::{ScanOrder, DEFAULT_SCAN_ORDER};
+
+pub const FILE_FORMAT_HEADER_LENGTH: usize = 32;
+pub const FILE_FORMAT_MAGIC: [u8; 4] = *b"SPZA";
+pub const FILE_FORMAT_VERSION: u8 = 5; //version 2 added the parity section, version 3 the encoding strategy of each image, version 4 the scan order, version 5 the flags
+pub const FILE_FORMAT_OLDEST_VERSION: u8 = 1;
+const FLAG_CONTEXT_CODED_BITMAP: u8 = 1;
 pub const BROTLI_BUFFER_SIZE: usize = 4096; //buffer size used for compression and decompression
 
+/// The fixed size header at the start of every archive
+/// format                              data name
+///---------------------------------------------------------------------------------------------------
+///[4 bytes]                            magic ('SPZA')
+///[u8]                                 format version
+///[u8]                                 codec id of the image data streams
+///[u8]                                 codec id of the bitmap streams
+///[u8]                                 codec id of the metadata
+///[u64, little endian]                 metadata_start_index
+///[u64, little endian]                 parity_start_index (0 if the archive has no parity section, always 0 in version 1)
+///[u8]                                 scan order id (zero before version 4, which means blocks of 50)
+///[u16, little endian]                 scan order block size (zero before version 4, and for orders without blocks)
+///[u8]                                 flags (zero before version 5) - bit 0: the bitmap streams are context coded (see bitmap_coder.rs)
+///[4 bytes]                            reserved (zero)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveHeader {
+    pub version : u8,
+    pub image_codec : Codec,
+    pub bitmap_codec : Codec,
+    pub metadata_codec : Codec,
+    pub metadata_start : u64,
+    //where the Reed-Solomon parity section starts (see parity.rs), which is also where the metadata ends
+    pub parity_start : u64,
+    //the order pixels are visited in by the encoding strategies (see scan_order.rs)
+    pub scan_order : ScanOrder,
+    //the bitmap streams are coded by the context-modeling bitmap coder instead of bitmap_codec
+    pub context_coded_bitmap : bool,
+}
+
+impl ArchiveHeader {
+    pub fn to_bytes(&self) -> [u8; FILE_FORMAT_HEADER_LENGTH]
+    {
+        let mut bytes = [0u8; FILE_FORMAT_HEADER_LENGTH];
+        bytes[0..4].copy_from_slice(&FILE_FORMAT_MAGIC);
+        bytes[4] = self.version;
+        bytes[5] = self.image_codec.id();
+        bytes[6] = self.bitmap_codec.id();
+        bytes[7] = self.metadata_codec.id();
+        bytes[8..16].copy_from_slice(&u64_to_u8_buf_little_endian(self.metadata_start));
+        bytes[16..24].copy_from_slice(&u64_to_u8_buf_little_endian(self.parity_start));
+        bytes[24..27].copy_from_slice(&self.scan_order.to_bytes());
+        bytes[27] = if self.context_coded_bitmap { FLAG_CONTEXT_CODED_BITMAP } else { 0 };
+        bytes
+    }
+
+    pub fn from_bytes(bytes : &[u8; FILE_FORMAT_HEADER_LENGTH]) -> Result<ArchiveHeader, String>
+    {
+        if bytes[0..4] != FILE_FORMAT_MAGIC {
+            return Err("Not a spritezip archive, or archive was made with an older version of spritezip".to_string());
+        }
+
+        let version = bytes[4];
+        if !(FILE_FORMAT_OLDEST_VERSION..=FILE_FORMAT_VERSION).contains(&version) {
+            return Err(format!("Unsupported archive version {} (this version of spritezip reads versions {} to {})",
+                               version, FILE_FORMAT_OLDEST_VERSION, FILE_FORMAT_VERSION));
+        }
+
+        let mut metadata_start_bytes = [0u8; 8];
+        metadata_start_bytes.copy_from_slice(&bytes[8..16]);
+        let mut parity_start_bytes = [0u8; 8];
+        parity_start_bytes.copy_from_slice(&bytes[16..24]);
+        let scan_order = if version < 4 {
+            DEFAULT_SCAN_ORDER
+        }
+        else {
+            let mut scan_order_bytes = [0u8; 3];
+            scan_order_bytes.copy_from_slice(&bytes[24..27]);
+            ScanOrder::from_bytes(&scan_order_bytes)?
+        };
+
+        let flags = if version < 5 { 0 } else { bytes[27] };
+        if flags & !FLAG_CONTEXT_CODED_BITMAP != 0 {
+            return Err(format!("Unknown archive flags {:#04x}", flags));
+        }
+
+        Ok(ArchiveHeader {
+            version,
+            image_codec : Codec::from_id(bytes[5])?,
+            bitmap_codec : Codec::from_id(bytes[6])?,
+            metadata_codec : Codec::from_id(bytes[7])?,
+            metadata_start : u8_buf_to_u64_little_endian(&metadata_start_bytes),
+            parity_start : u8_buf_to_u64_little_endian(&parity_start_bytes),
+            scan_order,
+            context_coded_bitmap : flags & FLAG_CONTEXT_CODED_BITMAP != 0,
+        })
+    }
+
+    /// The end of the metadata - either the start of the parity section, or the end of the file if there isn't one
+    pub fn metadata_end(&self, file_length : u64) -> u64
+    {
+        if self.parity_start == 0 { file_length } else { self.parity_start }
+    }
+}
+
+/// The path of a temporary file, next to the archive being written. The archive is only renamed over the
+/// target once it is complete - if compression fails or panics part way, the temporary file is deleted
+/// when this is dropped, and any existing archive is left untouched.
+pub struct TemporaryArchivePath {
+    pub path : PathBuf,
+    target_path : PathBuf,
+    persisted : bool,
+}
+
+impl TemporaryArchivePath {
+    pub fn new(target_path : &Path) -> TemporaryArchivePath
+    {
+        //same directory as the target, so the rename can't cross filesystems
+        let mut temp_file_name = target_path.file_name().unwrap_or_default().to_os_string();
+        temp_file_name.push(".tmp");
+
+        TemporaryArchivePath {
+            path : target_path.with_file_name(temp_file_name),
+            target_path : target_path.to_path_buf(),
+            persisted : false,
+        }
+    }
+
+    /// Replaces the target with the temporary file. The file must already be synced and closed.
+    pub fn persist(mut self) -> Result<(), String>
+    {
+        fs::rename(&self.path, &self.target_path)
+            .map_err(|e| format!("Unable to rename {} to {}: {}", self.path.display(), self.target_path.display(), e))?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TemporaryArchivePath {
+    fn drop(&mut self)
+    {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
 pub fn get_offset_to_other_image(original_image : &image::RgbaImage, prev_image : &image::RgbaImage) -> (i64, i64)
 {
     let prev_x_offset = (prev_image.width() as i64 - original_image.width()  as i64)/2;
@@ -25,10 +170,24 @@ pub fn get_offset_to_other_image(original_image : &image::RgbaImage, prev_image
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DecompressionInfo {
     pub canvas_size: (u32, u32),
-    pub bitmap_data_start : u64,
+    pub blocks: Vec<SolidBlockInfo>,
     pub images_info:  Vec<CompressedImageInfo>,
 }
 
+/// A run of consecutive images whose image data and bitmap are compressed as their own brotli streams.
+/// The first image of each block is diffed against the null image (it is a keyframe), so each block
+/// can be decoded without reading any other block.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SolidBlockInfo {
+    //index into images_info of the first image in this block
+    pub first_image: usize,
+    pub num_images: usize,
+    //file offsets of the block's compressed image data, compressed bitmap, and the end of the block
+    pub image_data_start: u64,
+    pub bitmap_data_start: u64,
+    pub block_end: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CompressedImageInfo {
     pub start_index: usize,
@@ -44,6 +203,136 @@ pub struct CompressedImageInfo {
     pub output_height: u32,
     //the output path of the image
     pub output_path: String,
+    //if this image is byte-identical to an earlier image, the index (into images_info) of that image.
+    //no image data or bitmap is stored for duplicates, and they aren't used as the reference for the next image
+    pub duplicate_of: Option<usize>,
+    //transform applied to the previous image before it is used as the reference for this image
+    pub reference_transform: ReferenceTransform,
+    //id of the EncodingStrategy (see strategy.rs) the image was encoded with
+    pub strategy: u8,
+}
+
+/// DecompressionInfo as stored by format versions 1 and 2, before each image had an encoding strategy.
+/// bincode isn't self-describing, so older metadata has to be read with its own structs.
+#[derive(Deserialize)]
+pub struct DecompressionInfoV2 {
+    canvas_size: (u32, u32),
+    blocks: Vec<SolidBlockInfo>,
+    images_info: Vec<CompressedImageInfoV2>,
+}
+
+#[derive(Deserialize)]
+struct CompressedImageInfoV2 {
+    start_index: usize,
+    x: u32,
+    y: u32,
+    diff_width: u32,
+    diff_height: u32,
+    output_width: u32,
+    output_height: u32,
+    output_path: String,
+    duplicate_of: Option<usize>,
+    reference_transform: ReferenceTransform,
+}
+
+impl From<DecompressionInfoV2> for DecompressionInfo {
+    fn from(info : DecompressionInfoV2) -> DecompressionInfo
+    {
+        DecompressionInfo {
+            canvas_size: info.canvas_size,
+            blocks: info.blocks,
+            images_info: info.images_info.into_iter().map(|image_info| CompressedImageInfo {
+                start_index: image_info.start_index,
+                x: image_info.x,
+                y: image_info.y,
+                diff_width: image_info.diff_width,
+                diff_height: image_info.diff_height,
+                output_width: image_info.output_width,
+                output_height: image_info.output_height,
+                output_path: image_info.output_path,
+                duplicate_of: image_info.duplicate_of,
+                reference_transform: image_info.reference_transform,
+                //every image used to be encoded with what is now the baseline strategy
+                strategy: 0,
+            }).collect(),
+        }
+    }
+}
+
+/// A transform applied to the previous image before diffing against it. Sprites of characters facing
+/// left and right are often exact mirrors of each other, which would otherwise be a 100% difference.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReferenceTransform {
+    None,
+    FlipHorizontal,
+    FlipVertical,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl ReferenceTransform {
+    pub const ALL : [ReferenceTransform; 6] = [
+        ReferenceTransform::None,
+        ReferenceTransform::FlipHorizontal,
+        ReferenceTransform::FlipVertical,
+        ReferenceTransform::Rotate90,
+        ReferenceTransform::Rotate180,
+        ReferenceTransform::Rotate270,
+    ];
+
+    /// Returns a transformed copy of the image (rotations are clockwise)
+    pub fn apply(&self, image : &RgbaImage) -> RgbaImage
+    {
+        match self {
+            ReferenceTransform::None => image.clone(),
+            ReferenceTransform::FlipHorizontal => image::imageops::flip_horizontal(image),
+            ReferenceTransform::FlipVertical => image::imageops::flip_vertical(image),
+            ReferenceTransform::Rotate90 => image::imageops::rotate90(image),
+            ReferenceTransform::Rotate180 => image::imageops::rotate180(image),
+            ReferenceTransform::Rotate270 => image::imageops::rotate270(image),
+        }
+    }
+}
+
+/// SHA-256 of an image's dimensions and raw RGBA pixels. Used to find byte-identical images.
+pub fn hash_image(image : &RgbaImage) -> [u8; 32]
+{
+    let mut hasher = Sha256::new();
+    hasher.update(u64_to_u8_buf_little_endian(image.width() as u64));
+    hasher.update(u64_to_u8_buf_little_endian(image.height() as u64));
+    hasher.update(image.as_raw());
+    hasher.finalize().into()
+}
+
+/// Converts a path stored in an archive into a relative path which can safely be joined onto the output folder.
+/// Both '/' and '\\' are treated as separators, and '.' components are removed. Absolute paths, '..' components,
+/// drive prefixes (anything containing ':') and NUL bytes are rejected, as they could write outside the output folder.
+pub fn sanitize_archive_path(stored_path : &str) -> Result<PathBuf, String>
+{
+    if stored_path.contains('\0') {
+        return Err(format!("Archive path {:?} contains a NUL byte", stored_path));
+    }
+
+    if stored_path.starts_with('/') || stored_path.starts_with('\\') {
+        return Err(format!("Archive path {:?} is an absolute path", stored_path));
+    }
+
+    let mut sanitized_path = PathBuf::new();
+    for component in stored_path.split(['/', '\\']) {
+        match component {
+            "" | "." => continue,
+            ".." => return Err(format!("Archive path {:?} contains a '..' component", stored_path)),
+            _ if component.contains(':') => return Err(format!("Archive path {:?} contains a drive prefix or ':'", stored_path)),
+            _ => sanitized_path.push(component),
+        }
+    }
+
+    if sanitized_path.as_os_str().is_empty() {
+        return Err(format!("Archive path {:?} is empty", stored_path));
+    }
+
+    Ok(sanitized_path)
 }
 
 pub fn pretty_print_bytes(value : f64) -> String
@@ -77,6 +366,42 @@ pub fn pretty_print_percent(numerator: u64, denominator: u64) -> String
 //}
 
 
+/// A folder in the system temp directory, which is deleted along with everything in it when this is dropped
+/// (even if the self test or a round trip test fails part way)
+pub struct ScratchFolder {
+    pub path : PathBuf,
+}
+
+impl ScratchFolder {
+    /// Creates a new, empty folder. The name has an unpredictable suffix, and a folder which already exists is
+    /// never reused (it might belong to someone else, and this one is deleted recursively).
+    pub fn new() -> Result<ScratchFolder, String>
+    {
+        let mut builder = fs::DirBuilder::new();
+        //only readable by this user
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+        for attempt in 0..100u32 {
+            let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|time| time.subsec_nanos()).unwrap_or(0);
+            let path = env::temp_dir().join(format!("spritezip_scratch_{}_{:08x}{:02x}", std::process::id(), nanos, attempt));
+            match builder.create(&path) {
+                Ok(()) => return Ok(ScratchFolder { path }),
+                Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(format!("Unable to create scratch folder {}: {}", path.display(), e)),
+            }
+        }
+        Err(format!("Unable to create a new scratch folder in {}", env::temp_dir().display()))
+    }
+}
+
+impl Drop for ScratchFolder {
+    fn drop(&mut self)
+    {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
 pub struct FileTypeIterator<'s> {
     walkdir_iterator : walkdir::IntoIter,
     file_type : &'s str,
@@ -86,7 +411,8 @@ impl<'s> FileTypeIterator<'s> {
     pub fn new(root : &'s str, file_type : &'s str) -> FileTypeIterator<'s>
     {
         FileTypeIterator {
-            walkdir_iterator : walkdir::WalkDir::new(root).into_iter(),
+            //sorted, so similar images (which are usually named alike) are next to each other
+            walkdir_iterator : walkdir::WalkDir::new(root).sort_by_file_name().into_iter(),
             file_type : file_type,
         }
     }
@@ -120,74 +446,6 @@ type Item = walkdir::DirEntry;
 	}
 }
 
-pub enum VerificationResult {
-    ExactMatch,     //images match exactly
-    InvisibleMatch, //images match, except for pixels whose alpha values are 0
-    Failure,         //images do not match
-    NotFound,
-}
-
-pub fn verify_images(input_folder : &str, output_folder : &str) -> VerificationResult
-{
-    //iterate over each image in input folder
-    let mut invisible_error = false;
-    for (img_count, ent) in FileTypeIterator::new(input_folder, "png").enumerate()
-    {
-        //load input and output images
-        let input_image_raw = image::open(ent.path()).unwrap().to_rgba8().into_raw();
-
-        let path_relative_to_input_folder = ent.path().strip_prefix(input_folder).unwrap();
-        let output_folder_image_path = Path::new(output_folder).join(path_relative_to_input_folder);
-
-        match image::open(&output_folder_image_path) {
-            Ok(output_image) =>  {
-                let output_image_raw = output_image.to_rgba8().into_raw();
-
-                println!("Comparing '{}' against '{}'...", ent.path().to_str().unwrap(), output_folder_image_path.to_str().unwrap());
-
-                //TODO: don't use this silly array indexing...
-                let mut invisible_pixel_found = false;
-                for pixel_i in 0..input_image_raw.len()/4
-                {
-                    let i = pixel_i * 4;
-                    let input_pixel = &input_image_raw[i..i + 4];
-                    let output_pixel = &output_image_raw[i..i + 4];
-
-                    if input_pixel != output_pixel {
-                        //if both pixel's alpha values are 0, mark as invisible pixel
-                        if (input_pixel[3]) == 0 && (output_pixel[3] == 0) {
-                            invisible_pixel_found = true;
-                        }
-                        else
-                        {
-                            //found a really wrong pixel, just exit immediately
-                            println!("Error: image {} does not match (true error)!", ent.path().to_str().unwrap());
-                            println!("{:?} != {:?}", input_pixel, output_pixel);
-                            return VerificationResult::Failure;
-                        }
-                    }
-                }
-
-                if invisible_pixel_found {
-                    println!("WARNING: invisible pixel found");
-                    invisible_error = true;
-                }
-             }
-
-            Err(e) => {
-                println!("{}", e.to_string());
-                return VerificationResult::NotFound;
-            }
-        }
-    }
-
-    if invisible_error {
-        return VerificationResult::InvisibleMatch;
-    }
-
-    return VerificationResult::ExactMatch;
-}
-
 pub fn get_byte_of_u64(value : u64, which_byte : usize) -> u8
 {
     return (value >> (which_byte * 8)) as u8;
@@ -287,9 +545,9 @@ pub fn scan_folder_for_max_png_size(input_folder : &str) -> (u32, u32)
     (max_width, max_height)
 }
 
-pub fn save_image_no_alpha(mut image : RgbaImage, save_path : &str)
+/// Makes every pixel fully opaque, so the colour of transparent pixels can be seen
+pub fn remove_alpha(image : &mut RgbaImage)
 {
-    println!("WARNING: Saving {} in Debug Mode: Alpha channel ignored!", save_path);
     for pixel in image.pixels_mut()
     {
         *pixel = image::Rgba([
@@ -299,6 +557,12 @@ pub fn save_image_no_alpha(mut image : RgbaImage, save_path : &str)
             255
         ]);
     }
+}
+
+pub fn save_image_no_alpha(mut image : RgbaImage, save_path : &str)
+{
+    println!("WARNING: Saving {} in Debug Mode: Alpha channel ignored!", save_path);
+    remove_alpha(&mut image);
 
     let save_path = Path::new("debug_images").join(save_path);
     fs::create_dir_all(save_path.parent().unwrap()).unwrap();
@@ -306,163 +570,53 @@ pub fn save_image_no_alpha(mut image : RgbaImage, save_path : &str)
     image.save(save_path).unwrap()
 }
 
-pub fn compress_image_to_buffer(img: &image::RgbaImage) -> Vec<u8>
+pub fn compress_image_to_buffer(img: &image::RgbaImage, settings : &CodecSettings) -> Result<Vec<u8>, String>
 {
-    let mut retvec = Vec::with_capacity(10000);
-    let imgclone = img.clone();
-    {
-        let mut compressor = brotli::CompressorWriter::new(
-            &mut retvec,
-            BROTLI_BUFFER_SIZE,
-            11,
-            24);
-
-        compressor.write(&imgclone.into_vec()).unwrap();
-    }
-
-    return retvec;
+    compress_buffer(img.as_raw(), settings)
 }
 
 
-pub fn compress_buffer(img: &Vec<u8>) -> Vec<u8>
+pub fn compress_buffer(buf: &[u8], settings : &CodecSettings) -> Result<Vec<u8>, String>
 {
     let mut retvec = Vec::with_capacity(10000);
-    let imgclone = img.clone();
     {
-        let mut compressor = brotli::CompressorWriter::new(
-            &mut retvec,
-            BROTLI_BUFFER_SIZE,
-            11,
-            24);
-
-        compressor.write(&imgclone).unwrap();
+        let mut compressor = new_compressor(&mut retvec, settings)?;
+        compressor.write_all(buf).map_err(|e| format!("Unable to compress buffer: {}", e))?;
     }
 
-    return retvec;
-}
-
-
-pub struct BlockXYIterator {
-    block_size : usize,
-    dimensions : (usize, usize),
-    num_x_blocks : usize,
-    num_y_blocks : usize,
-    i : usize,
+    Ok(retvec)
 }
 
-impl BlockXYIterator {
-    pub fn new(block_size : usize, dimensions : (usize, usize)) -> BlockXYIterator
-    {
-        let mut safe_dimensions = dimensions;
-        let mut safe_i = 0;
-
-        // if dimensions given are 0,0, force dimensions to 1,1, then set i so that iterator
-        // will terminate immediately. This avoids a modulo (%) by zero error and does 0 iterations.
-        if dimensions.0 == 0 || dimensions.1 == 0 {
-            safe_dimensions = (1, 1);
-            safe_i = 2;
-        }
 
-        let num_x_blocks = safe_dimensions.0 / block_size + if safe_dimensions.0 % block_size == 0 {0} else {1};
-        let num_y_blocks = safe_dimensions.1 / block_size + if safe_dimensions.1 % block_size == 0 {0} else {1};
+pub fn try_get_pixel(prev_xy : (i64, i64), prev_image : &image::RgbaImage) -> Option<image::Rgba<u8>>
+{
+    let prev_x = prev_xy.0; //original_pixel_xy.0 + prev_x_offset;
+    let prev_y = prev_xy.1; //original_pixel_xy.1 + prev_y_offset;
 
-        BlockXYIterator {
-            block_size,
-            dimensions : safe_dimensions,
-            num_x_blocks,
-            num_y_blocks,
-            i : safe_i,
-        }
+    if prev_x < 0 || prev_y < 0 || prev_x >= prev_image.width() as i64 || prev_y >= prev_image.height() as i64 {
+        return None;
     }
+
+    return Some(*prev_image.get_pixel(prev_x as u32, prev_y as u32));
 }
 
-impl Iterator for BlockXYIterator {
-type Item = (u32, u32);
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
 
-//	//use this one?
+    use super::sanitize_archive_path;
 
-    fn next(&mut self) -> Option<Self::Item>
+    #[test]
+    fn unsafe_archive_paths_are_rejected()
     {
-        if true {
-            loop {
-                let x_in_block = self.i % self.block_size;
-                let y_in_block = (self.i / self.block_size) % self.block_size;
-
-                let x_block = (self.i / (self.block_size * self.block_size)) % self.num_x_blocks;
-                let y_block = self.i / (self.block_size * self.block_size * self.num_x_blocks);
-
-                //println!("x_block: {} y_block: {}", x_block, y_block);
-
-                let returned_x = (x_in_block + x_block * self.block_size) as u32;
-                let returned_y = (y_in_block + y_block * self.block_size) as u32;
-
-                self.i += 1;
-
-                let x_in_range = returned_x < (self.dimensions.0 as u32);
-                let y_in_range = returned_y < (self.dimensions.1 as u32);
-
-                if x_in_range && y_in_range {
-                    return Some((returned_x, returned_y));
-                } else if y_block >= self.num_y_blocks {
-                    return None;
-                }
-            }
-        } else {
-
-            let x = (self.i % self.dimensions.0) as u32;
-            let y = (self.i / self.dimensions.0) as u32;
-
-            self.i += 1;
-
-            if y < self.dimensions.1 as u32 {
-                Some((x, y))
-            }
-            else {
-                None
-            }
+        for stored_path in ["../x.png", "a/../../x", "/etc/passwd", "\\\\server\\x", "C:\\x", "C:x", "a\0b", "", "./."].iter() {
+            assert!(sanitize_archive_path(stored_path).is_err(), "{:?} was accepted", stored_path);
         }
     }
-}
-
-
-
-
-/*
-Tests for xy iterator
-
-//test iterating (50, width = 0, height = 0)
-//test odd/even pixel width
-//test images equal exactly the block width, and not equal to block width
-
-#[allow(unused_imports)]
-use common::BlockXYIterator;
-#[allow(unused_imports)]
-use image::RgbaImage;
-
-
-  test for iterator
-
-  let im = RgbaImage::new(3,5);
-    let mut count = 0;
-   for (x,y) in BlockXYIterator::new(2, (3, 5)) {
-       count += 1;
-       let pix = im.get_pixel(x,y);
-       println!("x: {} y:{} {:?} {}", x,y, pix, count);
-    }
-
-
-    return;
-*/
-
-
-pub fn try_get_pixel(prev_xy : (i64, i64), prev_image : &image::RgbaImage) -> Option<image::Rgba<u8>>
-{
-    let prev_x = prev_xy.0; //original_pixel_xy.0 + prev_x_offset;
-    let prev_y = prev_xy.1; //original_pixel_xy.1 + prev_y_offset;
 
-    if prev_x < 0 || prev_y < 0 || prev_x >= prev_image.width() as i64 || prev_y >= prev_image.height() as i64 {
-        return None;
+    #[test]
+    fn archive_paths_are_normalized()
+    {
+        assert_eq!(sanitize_archive_path("a\\b/./c.png"), Ok(Path::new("a").join("b").join("c.png")));
     }
-
-    return Some(*prev_image.get_pixel(prev_x as u32, prev_y as u32));
-}
\ No newline at end of file
+}
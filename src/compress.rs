@@ -1,25 +1,37 @@
 //standard uses
 use std;
-use std::io::{Write};
-use std::fs::File;
+use std::io::{Write, BufReader};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
 use std::io::SeekFrom;
 use std::io::Seek;
 use std::iter::Iterator;
+use std::collections::{HashMap, HashSet};
 
 //non-standard use
 use bincode;
 
-use brotli;
 use image;
+use walkdir;
 
 use common::{pretty_print_bytes, pretty_print_percent};
-use common::{CompressedImageInfo, DecompressionInfo};
-use common::u64_to_u8_buf_little_endian;
-use common::{FILE_FORMAT_HEADER_LENGTH, BROTLI_BUFFER_SIZE};
+use common::{CompressedImageInfo, DecompressionInfo, SolidBlockInfo};
+use common::ReferenceTransform;
+use common::{ArchiveHeader, FILE_FORMAT_HEADER_LENGTH, FILE_FORMAT_VERSION};
 use common::get_offset_to_other_image;
-use common::BlockXYIterator;
 use common::try_get_pixel;
 use common::FileTypeIterator;
+use common::get_png_dimensions;
+use common::hash_image;
+use common::TemporaryArchivePath;
+use common::BROTLI_BUFFER_SIZE;
+use codec::{Codec, CodecSettings, new_compressor};
+use byte_counter::ByteCounter;
+use stats::{CompressionStats, ImageStats, BlockStats};
+use parity::{append_parity, parity_shards_for_percent};
+use bitmap_coder::BitmapStreamWriter;
+use scan_order::ScanOrder;
+use strategy::{strategy_by_id, DiffStrategy, BASELINE_STRATEGY, BIDIRECTIONAL_STRATEGY, STRATEGIES};
 
 struct CroppedImageBounds {
     x : u32,
@@ -78,7 +90,8 @@ fn crop_function(img: &image::RgbaImage, offset : (u32, u32), max_width : u32, m
     num_identical_pixels)
 }
 
-struct Cropper {
+/// Finds the smallest rectangle containing every pixel added to it
+pub struct Cropper {
     min_x: u32,
     max_x: u32,
     min_y: u32,
@@ -87,13 +100,13 @@ struct Cropper {
 
 #[derive(Debug)]
 pub struct CropRegion {
-    top_left : (u32, u32),
-    dimensions : (u32, u32),
+    pub top_left : (u32, u32),
+    pub dimensions : (u32, u32),
 }
 
 impl Cropper {
 
-    fn new(image_dimensions : (u32, u32)) -> Cropper {
+    pub fn new(image_dimensions : (u32, u32)) -> Cropper {
         Cropper {
             //set each bound to its worst case value
             min_x: image_dimensions.0,
@@ -103,7 +116,7 @@ impl Cropper {
         }
     }
 
-    fn add_nonzero_pixel(&mut self, x : u32, y : u32)
+    pub fn add_nonzero_pixel(&mut self, x : u32, y : u32)
     {
         //reduce each bound
         self.min_x = std::cmp::min(self.min_x, x);
@@ -115,7 +128,7 @@ impl Cropper {
 
     //not sure whether to return None or a zero size crop region here
     //I guess a zero size crop region is more generic, so I'll do that
-    fn get_crop_region(&self) -> CropRegion
+    pub fn get_crop_region(&self) -> CropRegion
     {
         //if the min x is greater than the max x, it means the image is empty (no pixels ever recorded)
         if self.min_x > self.max_x {
@@ -134,188 +147,526 @@ impl Cropper {
 }
 
 
-pub struct BlockImageIterator<'s> {
-    original_image : &'s image::RgbaImage,
-    xy_iter : BlockXYIterator,
+/// Limits on how many images go into each solid block. A new block (which begins with a keyframe
+/// and has its own brotli streams) is started when adding the next image would exceed either limit.
+/// If neither limit is set, the whole archive is a single solid block.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SolidBlockLimits {
+    pub max_images : Option<usize>,
+    pub max_uncompressed_bytes : Option<u64>,
 }
 
-impl<'s> BlockImageIterator<'s> {
-    fn new(original_image : &'s image::RgbaImage, block_size : usize) -> BlockImageIterator<'s>
+/// Splits the list of images into runs which will each be compressed as one solid block.
+/// The size of an image is taken to be its uncompressed RGBA size, read from the .png header.
+pub fn split_into_solid_blocks<'a>(image_entries : &'a [walkdir::DirEntry], limits : &SolidBlockLimits) -> Vec<&'a [walkdir::DirEntry]>
+{
+    let mut blocks = Vec::new();
+    let mut block_start = 0;
+    let mut block_bytes = 0u64;
+
+    for (i, ent) in image_entries.iter().enumerate()
     {
-        BlockImageIterator {
-            original_image,
-            xy_iter : BlockXYIterator::new(block_size, (original_image.width() as usize, original_image.height() as usize)),
+        let mut reader = BufReader::new(File::open(ent.path()).unwrap());
+        let (width, height) = get_png_dimensions(&mut reader).expect("Could not read png file dimensions!");
+        let image_bytes = width as u64 * height as u64 * 4;
+
+        let num_images_in_block = i - block_start;
+        let too_many_images = limits.max_images.is_some_and(|max| num_images_in_block >= max);
+        let too_many_bytes = limits.max_uncompressed_bytes.is_some_and(|max| block_bytes + image_bytes > max);
+
+        //never start a new block when the current one is empty, so an image larger than the limit gets its own block
+        if num_images_in_block > 0 && (too_many_images || too_many_bytes) {
+            blocks.push(&image_entries[block_start..i]);
+            block_start = i;
+            block_bytes = 0;
         }
+
+        block_bytes += image_bytes;
     }
-}
 
-impl<'s> Iterator for BlockImageIterator<'s>  {
-type Item = (u32, u32, image::Rgba < u8 >);
-	//use this one?
-	fn next(&mut self) -> Option<Self::Item>
-    {
-        match self.xy_iter.next() {
-            None => None,
-            Some((x,y)) => Some((x,y, *self.original_image.get_pixel(x, y))),
-        }
-	}
+    if block_start < image_entries.len() {
+        blocks.push(&image_entries[block_start..]);
+    }
+
+    blocks
 }
 
+/// All user configurable compression settings
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompressionSettings {
+    pub block_limits : SolidBlockLimits,
+    pub image_codec : CodecSettings,
+    pub bitmap_codec : CodecSettings,
+    pub metadata_codec : CodecSettings,
+    //if set, a Reed-Solomon parity section of roughly this percentage of the archive size is appended
+    pub parity_percent : Option<u32>,
+    //try every encoding strategy on each image, and keep the one which compresses smallest
+    pub auto_strategy : bool,
+    //the id of the strategy every image is encoded with when auto_strategy isn't set (0, the default, is baseline)
+    pub strategy : u8,
+    //the order the pixels of each image are scanned in (stored in the archive header)
+    pub scan_order : ScanOrder,
+    //code the bitmap streams with the context-modeling bitmap coder instead of bitmap_codec
+    pub context_coded_bitmap : bool,
+    //store images as B-frames (predicted from the images before and after them) where that is estimated to be smaller
+    pub bidirectional : bool,
+}
 
-pub fn alt_compression_2(brotli_archive_path : &str)
+/// Compresses every .png image in input_folder into an archive, returning statistics about what each image added to it
+pub fn alt_compression_2(input_folder : &str, brotli_archive_path : &str, settings : &CompressionSettings) -> Result<CompressionStats, String>
 {
-    let brotli_quality = 11;
-    let brotli_window = 24;
+    println!("Image data: {:?}", settings.image_codec);
+    println!("Bitmap: {:?}", settings.bitmap_codec);
+    println!("Metadata: {:?}", settings.metadata_codec);
+    println!("Scan order: {}", settings.scan_order);
+
+    //don't write an archive at all if there is nothing to put in it
+    let image_entries : Vec<walkdir::DirEntry> = FileTypeIterator::new(input_folder, "png").collect();
+    if image_entries.is_empty() {
+        return Err(format!("No .png images found in '{}' - no archive was written", input_folder));
+    }
 
     //Create object to store all image metadata (but not the global metadata)
     let mut images_info : Vec<CompressedImageInfo> = Vec::new();
+    let mut blocks : Vec<SolidBlockInfo> = Vec::new();
+    let mut stats = CompressionStats::default();
 
-    let mut compressed_bitmap_data_vector = Vec::new();
-
-    let mut archive_file = File::create(brotli_archive_path).expect("Cannot create file");
+    let temp_archive_path = TemporaryArchivePath::new(Path::new(brotli_archive_path));
+    //opened for reading as well, so the parity can be computed from what was written
+    let mut archive_file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&temp_archive_path.path)
+        .map_err(|e| format!("Unable to create {}: {}", temp_archive_path.path.display(), e))?;
 
     //Allocate some space for the file format header
-    archive_file.write(&[0; FILE_FORMAT_HEADER_LENGTH]).expect("Unable to allocate header space in file");
+    archive_file.write_all(&[0; FILE_FORMAT_HEADER_LENGTH]).expect("Unable to allocate header space in file");
 
-    //scope for compression/file objects (most of the work)
+    //hashes of every image stored so far, used to find exact duplicates
+    let mut image_hashes : HashMap<[u8; 32], usize> = HashMap::new();
+    let mut num_duplicates = 0;
+    //number of images encoded with each strategy
+    let mut strategy_wins = [0usize; STRATEGIES.len()];
+
+    for block_entries in split_into_solid_blocks(&image_entries, &settings.block_limits)
     {
-        //Create compressors for image data and bitmap
-        let mut image_compressor = brotli::CompressorWriter::new(&archive_file, BROTLI_BUFFER_SIZE, brotli_quality, brotli_window);
-        let mut bitmap_compressor = brotli::CompressorWriter::new(&mut compressed_bitmap_data_vector, BROTLI_BUFFER_SIZE, brotli_quality, brotli_window);
-
-        let mut prev_image = image::RgbaImage::new(0,0);
-
-        for (img_count, ent) in FileTypeIterator::new("input_images", "png").enumerate() {
-            let path_relative_to_input_folder = ent.path().strip_prefix("input_images").unwrap().to_str().unwrap();
-
-            let img_dyn = image::open(ent.path()).unwrap();
-            let image = img_dyn.as_rgba8().unwrap();
-
-            print!("{}: ", img_count + 1);
-
-            let crop_region = alt_compression_3_inner(&image, &prev_image, &mut image_compressor, &mut bitmap_compressor);
-            images_info.push(CompressedImageInfo {
-                start_index: 0, //not used
-                x: crop_region.top_left.0,
-                y: crop_region.top_left.1,
-                diff_width: crop_region.dimensions.0,
-                diff_height: crop_region.dimensions.1,
-                output_width: image.width(),
-                output_height: image.height(),
-                output_path: path_relative_to_input_folder.to_string(),
-            });
+        println!("\n---- Solid block {} ({} images) ----", blocks.len(), block_entries.len());
+
+        let first_image = images_info.len();
+        let image_data_start = archive_file.stream_position().unwrap();
 
-            prev_image = image.clone(); //TODO: remove this clone?
-            print!("{}", path_relative_to_input_folder);
-            println!("");
+        let mut compressed_bitmap_data_vector = Vec::new();
+
+        //scope for compression/file objects (most of the work)
+        {
+            //Create compressors for image data and bitmap, counting the (uncompressed) bytes written to each
+            let mut image_compressor = ByteCounter::new(new_compressor(&archive_file, &settings.image_codec)?, None);
+            let mut bitmap_stream = BitmapStreamWriter::new(&mut compressed_bitmap_data_vector, &settings.bitmap_codec, settings.context_coded_bitmap)?;
+            let mut block_bitmap_bytes = 0;
+
+            //every block starts from the null image, so it can be decoded without the blocks before it
+            let mut prev_image = image::RgbaImage::new(0,0);
+            //the image stored before prev_image, which B-frames are predicted from (along with prev_image)
+            let mut image_before_prev = image::RgbaImage::new(0,0);
+
+            //B-frames are stored after the image which follows them
+            let plan = if settings.bidirectional {
+                plan_bidirectional_frames(block_entries.iter().map(|ent| image::open(ent.path()).unwrap().to_rgba8()), &image_hashes, settings)?
+            }
+            else {
+                (0..block_entries.len()).map(|i| (i, false)).collect()
+            };
+
+            for &(entry_i, bidirectional) in &plan {
+                let ent = &block_entries[entry_i];
+                //always store paths with '/' separators, so archives made on windows extract correctly elsewhere
+                let path_relative_to_input_folder = ent.path().strip_prefix(input_folder).unwrap()
+                    .components()
+                    .map(|component| component.as_os_str().to_str().unwrap())
+                    .collect::<Vec<&str>>()
+                    .join("/");
+                let path_relative_to_input_folder = path_relative_to_input_folder.as_str();
+
+                let img_dyn = image::open(ent.path()).unwrap();
+                let image = img_dyn.as_rgba8().unwrap();
+
+                print!("{}: ", images_info.len() + 1);
+
+                //byte-identical images are stored as a reference to the first copy, and don't change prev_image
+                let image_hash = hash_image(image);
+                if let Some(&original_index) = image_hashes.get(&image_hash) {
+                    println!("Duplicate of {}: {}", images_info[original_index].output_path, path_relative_to_input_folder);
+                    images_info.push(CompressedImageInfo {
+                        start_index: 0, //not used
+                        x: 0,
+                        y: 0,
+                        diff_width: 0,
+                        diff_height: 0,
+                        output_width: image.width(),
+                        output_height: image.height(),
+                        output_path: path_relative_to_input_folder.to_string(),
+                        duplicate_of: Some(original_index),
+                        reference_transform: ReferenceTransform::None,
+                        strategy: BASELINE_STRATEGY.id(),
+                    });
+                    stats.images.push(ImageStats {
+                        path : path_relative_to_input_folder.to_string(),
+                        width : image.width(),
+                        height : image.height(),
+                        solid_block : blocks.len(),
+                        duplicate_of : Some(images_info[original_index].output_path.clone()),
+                        ..ImageStats::default()
+                    });
+                    num_duplicates += 1;
+                    continue;
+                }
+                image_hashes.insert(image_hash, images_info.len());
+
+                let (mut reference_transform, reference_image, next_image) = if bidirectional {
+                    (ReferenceTransform::None, image_before_prev.clone(), Some(&prev_image))
+                }
+                else {
+                    let (reference_transform, reference_image) = choose_reference_transform(image, &prev_image);
+                    (reference_transform, reference_image, None)
+                };
+                let strategy : &dyn DiffStrategy = if bidirectional { &BIDIRECTIONAL_STRATEGY } else { pick_strategy(settings, image, &reference_image)? };
+                if !strategy.uses_reference() {
+                    //the previous image isn't used at all
+                    reference_transform = ReferenceTransform::None;
+                }
+                strategy_wins[STRATEGIES.iter().position(|known| known.id() == strategy.id()).unwrap()] += 1;
+
+                if reference_transform != ReferenceTransform::None {
+                    print!("Ref: {:?} ", reference_transform);
+                }
+                if settings.auto_strategy || bidirectional {
+                    print!("Strategy: {:12} ", strategy.name());
+                }
+
+                //the image data is kept until it is compressed, so its compressed size can be estimated for the statistics
+                let mut image_data = Vec::new();
+                let mut bitmap = Vec::new();
+                let crop_region = alt_compression_3_inner(image, &reference_image, next_image, strategy, settings.scan_order, &mut image_data, &mut bitmap);
+                image_compressor.write_all(&image_data).map_err(|e| format!("Unable to compress image data: {}", e))?;
+                bitmap_stream.write_image(&bitmap, image.dimensions(), &crop_region, strategy.scan_order(settings.scan_order, crop_region.dimensions), bidirectional)?;
+                let image_bytes = image_data.len() as u64;
+                let bitmap_bytes = bitmap.len() as u64;
+                let estimated_compressed_bytes = (estimate_compressed_size(&image_data) + estimate_compressed_size(&bitmap)) as u64;
+                block_bitmap_bytes += bitmap_bytes;
+
+                //every pixel stored in the image data has a 1 in the bitmap
+                let changed_pixels = bitmap.iter().filter(|&&bitmap_value| bitmap_value == 1).count() as u64;
+                let num_pixels = image.width() as u64 * image.height() as u64;
+                stats.images.push(ImageStats {
+                    path : path_relative_to_input_folder.to_string(),
+                    width : image.width(),
+                    height : image.height(),
+                    solid_block : blocks.len(),
+                    duplicate_of : None,
+                    strategy : strategy.name(),
+                    diff_percent : if num_pixels == 0 { 0.0 } else { changed_pixels as f64 / num_pixels as f64 * 100.0 },
+                    crop_x : crop_region.top_left.0,
+                    crop_y : crop_region.top_left.1,
+                    crop_width : crop_region.dimensions.0,
+                    crop_height : crop_region.dimensions.1,
+                    changed_pixels,
+                    image_bytes,
+                    bitmap_bytes,
+                    estimated_compressed_bytes,
+                });
+                images_info.push(CompressedImageInfo {
+                    start_index: 0, //not used
+                    x: crop_region.top_left.0,
+                    y: crop_region.top_left.1,
+                    diff_width: crop_region.dimensions.0,
+                    diff_height: crop_region.dimensions.1,
+                    output_width: image.width(),
+                    output_height: image.height(),
+                    output_path: path_relative_to_input_folder.to_string(),
+                    duplicate_of: None,
+                    reference_transform,
+                    strategy: strategy.id(),
+                });
+
+                //B-frames aren't the reference for anything
+                if !bidirectional {
+                    image_before_prev = std::mem::replace(&mut prev_image, image.clone()); //TODO: remove this clone?
+                }
+                println!("{}", path_relative_to_input_folder);
+            }
+
+            stats.blocks.push(BlockStats {
+                num_images : block_entries.len(),
+                image_bytes : image_compressor.bytes_written() as u64,
+                bitmap_bytes : block_bitmap_bytes,
+                ..BlockStats::default()
+            });
+            bitmap_stream.finish()?;
         }
-    }
 
-    //Save the already compressed bitmap, recording where it starts in the file
-    let bitmap_data_start = archive_file.seek(SeekFrom::Current(0)).unwrap();
-    archive_file.write_all(&compressed_bitmap_data_vector).unwrap();
+        //Save the already compressed bitmap, recording where it starts in the file
+        let bitmap_data_start = archive_file.stream_position().unwrap();
+        archive_file.write_all(&compressed_bitmap_data_vector).unwrap();
+
+        let block_stats = stats.blocks.last_mut().unwrap();
+        block_stats.compressed_image_bytes = bitmap_data_start - image_data_start;
+        block_stats.compressed_bitmap_bytes = compressed_bitmap_data_vector.len() as u64;
+
+        blocks.push(SolidBlockInfo {
+            first_image,
+            num_images: images_info.len() - first_image,
+            image_data_start,
+            bitmap_data_start,
+            block_end: archive_file.stream_position().unwrap(),
+        });
+    }
 
     //Compress and save the metadata, recording the start location in the file
-    let metadata_start = archive_file.seek(SeekFrom::Current(0)).unwrap();
+    let metadata_start = archive_file.stream_position().unwrap();
     let decompression_info = DecompressionInfo {
         canvas_size: (0, 0), //TODO: remove this - it's not used
-        bitmap_data_start,
+        blocks,
         images_info,
     };
     let serialized_metadata = bincode::serialize(&decompression_info).unwrap();
     {
-        brotli::CompressorWriter::new(&archive_file, BROTLI_BUFFER_SIZE, brotli_quality, brotli_window)
+        new_compressor(&archive_file, &settings.metadata_codec)?
             .write_all(&serialized_metadata).unwrap();
     }
 
-    //save end of file location
-    let file_size = archive_file.seek(SeekFrom::Current(0)).unwrap();
+    //save end of metadata location
+    let file_size = archive_file.stream_position().unwrap();
 
-    //return to start of file to write metadata offset
+    //return to start of file to write the header (which holds the metadata offset)
+    let header = ArchiveHeader {
+        version : FILE_FORMAT_VERSION,
+        image_codec : settings.image_codec.codec,
+        bitmap_codec : settings.bitmap_codec.codec,
+        metadata_codec : settings.metadata_codec.codec,
+        metadata_start,
+        //the parity section (if any) starts right after the metadata
+        parity_start : if settings.parity_percent.is_some() { file_size } else { 0 },
+        scan_order : settings.scan_order,
+        context_coded_bitmap : settings.context_coded_bitmap,
+    };
     archive_file.seek(SeekFrom::Start(0)).unwrap();
-    archive_file.write(&u64_to_u8_buf_little_endian(metadata_start)).expect("Unable to write header offset to file");
+    archive_file.write_all(&header.to_bytes()).map_err(|e| format!("Unable to write header to file: {}", e))?;
+
+    //the parity protects the header too, so it is computed last
+    let parity_info = match settings.parity_percent {
+        Some(parity_percent) => Some(append_parity(&mut archive_file, file_size, parity_shards_for_percent(parity_percent))?),
+        None => None,
+    };
+    //(the file position is just after the header if there is no parity section)
+    let archive_size = archive_file.seek(SeekFrom::End(0)).unwrap();
+
+    //make sure the whole archive is on disk before it replaces the old one
+    archive_file.sync_all().map_err(|e| format!("Unable to flush archive to disk: {}", e))?;
+    drop(archive_file);
+    temp_archive_path.persist()?;
 
     //Print debug information
-    let bitmap_data_length = metadata_start - bitmap_data_start;
+    let image_data_length : u64 = decompression_info.blocks.iter().map(|block| block.bitmap_data_start - block.image_data_start).sum();
+    let bitmap_data_length : u64 = decompression_info.blocks.iter().map(|block| block.block_end - block.bitmap_data_start).sum();
     let metadata_length_bytes = file_size - metadata_start;
 
     println!("\n\n ------------ Compression Finished! ------------");
-    println!("Total archive size is {}", pretty_print_bytes(file_size as f64));
+    println!("Total archive size is {}", pretty_print_bytes(archive_size as f64));
+    println!("Archive has {} solid block(s)", decompression_info.blocks.len());
+    println!("{} image(s) were stored as duplicates of an earlier image", num_duplicates);
+    println!("Image data is {}, {} of total",
+             pretty_print_bytes(image_data_length as f64),
+             pretty_print_percent(image_data_length, archive_size));
     println!("Bitmap data is {}, {} of total",
              pretty_print_bytes(bitmap_data_length as f64),
-             pretty_print_percent(bitmap_data_length, file_size));
+             pretty_print_percent(bitmap_data_length, archive_size));
 
     println!("Metadata is {} ({} uncompressed), {} of total",
              pretty_print_bytes(metadata_length_bytes as f64),
              pretty_print_bytes(serialized_metadata.len() as f64),
-             pretty_print_percent(metadata_length_bytes, file_size));
+             pretty_print_percent(metadata_length_bytes, archive_size));
+
+    if settings.bidirectional {
+        let num_b_frames = strategy_wins[STRATEGIES.iter().position(|known| known.is_bidirectional()).unwrap()];
+        println!("{} image(s) were stored as B-frames", num_b_frames);
+    }
+
+    if settings.auto_strategy {
+        println!("Encoding strategies chosen by --auto:");
+        for (strategy, &wins) in STRATEGIES.iter().zip(strategy_wins.iter()) {
+            println!("{:>8} image(s): {}", wins, strategy.name());
+        }
+    }
+
+    if let Some(parity_info) = parity_info {
+        println!("Parity data is {} ({} shards of {}, up to {} damaged shards can be repaired), {} of total",
+                 pretty_print_bytes((archive_size - file_size) as f64),
+                 parity_info.data_shards + parity_info.parity_shards,
+                 pretty_print_bytes(parity_info.shard_size as f64),
+                 parity_info.parity_shards,
+                 pretty_print_percent(archive_size - file_size, archive_size));
+    }
+
+    Ok(stats)
 }
 
-pub fn alt_compression_3_inner<'s,T,V>(original_image : &image::RgbaImage, prev_image : &image::RgbaImage, image_compressor : &'s mut brotli::CompressorWriter<T>, bitmap_compressor : &'s mut   brotli::CompressorWriter<V>) -> CropRegion
-where T: std::io::Write,
-      V: std::io::Write
+/// Counts the pixels of original_image which don't match the reference image when the two are aligned
+/// the same way as alt_compression_3_inner aligns them. Pixels outside the reference count as different.
+fn count_different_pixels(original_image : &image::RgbaImage, reference_image : &image::RgbaImage) -> u64
 {
-    let (x_offset_to_other_image , y_offset_to_other_image)= get_offset_to_other_image(original_image, prev_image);
+    let (x_offset_to_other_image , y_offset_to_other_image)= get_offset_to_other_image(original_image, reference_image);
 
-    // ----------------------------  DO CROP  ----------------------------
-    let mut cropper = Cropper::new((original_image.width(), original_image.height()));
-    let mut debug_difference_count = 0;
+    let mut difference_count = 0;
     for (x, y, original_image_pixel) in original_image.enumerate_pixels()
     {
-        let prev_x = x as i64 + x_offset_to_other_image;
-        let prev_y = y as i64 + y_offset_to_other_image;
+        let reference_xy = (x as i64 + x_offset_to_other_image, y as i64 + y_offset_to_other_image);
+        if try_get_pixel(reference_xy, reference_image) != Some(*original_image_pixel) {
+            difference_count += 1;
+        }
+    }
 
-        let pixels_equal = match try_get_pixel((prev_x, prev_y), &prev_image) {
-            None => false,
-            Some(prev_pixel) => *original_image_pixel == prev_pixel,
-        };
+    difference_count
+}
 
-        if !pixels_equal {
-            cropper.add_nonzero_pixel(x, y);
-            debug_difference_count += 1;
+/// Tries diffing against each flipped/rotated version of the previous image, and returns the transform
+/// (and transformed previous image) which has the fewest different pixels. Ties go to the untransformed image.
+pub fn choose_reference_transform(original_image : &image::RgbaImage, prev_image : &image::RgbaImage) -> (ReferenceTransform, image::RgbaImage)
+{
+    let mut best_transform = ReferenceTransform::None;
+    let mut best_reference = prev_image.clone();
+    let mut best_difference_count = count_different_pixels(original_image, prev_image);
+
+    for transform in ReferenceTransform::ALL.iter().skip(1)
+    {
+        //no point trying to transform the null image, and stop early if the images already match
+        if best_difference_count == 0 || prev_image.width() == 0 || prev_image.height() == 0 {
+            break;
+        }
+
+        let transformed_reference = transform.apply(prev_image);
+        let difference_count = count_different_pixels(original_image, &transformed_reference);
+        if difference_count < best_difference_count {
+            best_transform = *transform;
+            best_reference = transformed_reference;
+            best_difference_count = difference_count;
         }
     }
 
-    //Get a cropped version of the image to work on
-    let crop_region = cropper.get_crop_region();
-    print!("Diff: {} ", pretty_print_percent(debug_difference_count, original_image.width() as u64 * original_image.height() as u64));
-    print!("CropTL: ({:4},{:4}) ", crop_region.top_left.0, crop_region.top_left.1);
-    print!("CropSize: ({:4},{:4}) ", crop_region.dimensions.0, crop_region.dimensions.1);
+    (best_transform, best_reference)
+}
 
-    let cropped_image = image::imageops::crop(&mut original_image.clone(),
-    crop_region.top_left.0, crop_region.top_left.1,
-    crop_region.dimensions.0, crop_region.dimensions.1).to_image();
+/// Codec settings used to quickly estimate how well each strategy's streams will compress
+const STRATEGY_ESTIMATE_CODEC : CodecSettings = CodecSettings {
+    codec : Codec::Brotli,
+    quality : 5,
+    window : 22,
+    buffer_size : BROTLI_BUFFER_SIZE,
+};
 
-    // ----------------------------  DO COMPRESS  ----------------------------
-    let mut debug_difference_count = 0;
-    let mut difference : Vec<u8> = Vec::with_capacity(cropped_image.width() as usize * cropped_image.height() as usize);
+/// Number of bytes data compresses to with STRATEGY_ESTIMATE_CODEC
+fn estimate_compressed_size(data : &[u8]) -> usize
+{
+    let mut counter = ByteCounter::new(std::io::sink(), None);
+    new_compressor(&mut counter, &STRATEGY_ESTIMATE_CODEC).unwrap().write_all(data).unwrap();
+    counter.bytes_written()
+}
 
-    for (x,y,cropped_pixel) in BlockImageIterator::new(&cropped_image, 50)
-    {
-        let original_image_x = x + crop_region.top_left.0;
-        let original_image_y = y + crop_region.top_left.1;
-        let prev_x = original_image_x as i64 + x_offset_to_other_image;
-        let prev_y = original_image_y as i64 + y_offset_to_other_image;
-
-        let pixels_equal = match try_get_pixel((prev_x, prev_y), &prev_image) {
-            None => false,
-            Some(prev_pixel) => cropped_pixel == prev_pixel,
-        };
+/// Encodes the image with every strategy, and returns the one whose image data and bitmap compress smallest on their
+/// own with a fast codec. This is only an estimate - in the archive, the streams are compressed along with the other
+/// images. Ties go to the strategy with the lowest id.
+pub fn choose_strategy(original_image : &image::RgbaImage, reference_image : &image::RgbaImage, scan_order : ScanOrder) -> &'static dyn DiffStrategy
+{
+    //B-frames need the next image, so they are only used by plan_bidirectional_frames
+    *STRATEGIES.iter()
+        .filter(|strategy| !strategy.is_bidirectional())
+        .min_by_key(|strategy| estimate_encoded_size(**strategy, original_image, reference_image, None, scan_order))
+        .unwrap()
+}
+
+/// Number of bytes the image data and bitmap of the image compress to on their own with STRATEGY_ESTIMATE_CODEC
+fn estimate_encoded_size(strategy : &dyn DiffStrategy, original_image : &image::RgbaImage, reference_image : &image::RgbaImage, next_image : Option<&image::RgbaImage>, scan_order : ScanOrder) -> usize
+{
+    let mut image_data = Vec::new();
+    let mut bitmap = Vec::new();
+    strategy.encode(original_image, reference_image, next_image, scan_order, &mut image_data, &mut bitmap);
+    estimate_compressed_size(&image_data) + estimate_compressed_size(&bitmap)
+}
+
+/// Estimated size of the image when it is predicted from prev_image the usual way
+fn estimate_stored_size(settings : &CompressionSettings, original_image : &image::RgbaImage, prev_image : &image::RgbaImage) -> Result<usize, String>
+{
+    let (_, reference_image) = choose_reference_transform(original_image, prev_image);
+    let strategy = pick_strategy(settings, original_image, &reference_image)?;
+    Ok(estimate_encoded_size(strategy, original_image, &reference_image, None, settings.scan_order))
+}
+
+/// The order the images of one solid block are stored in, as (index into images, whether it is a B-frame).
+/// Whenever an image is estimated to be smaller as a B-frame - predicted from the image stored before it and the
+/// image after it - than stored the usual way, the image after it is moved in front of it, so the extractor has
+/// both when it gets to the B-frame. Only images which are stored (not duplicates or B-frames) are predicted from,
+/// and duplicates are left where they are. stored_hashes holds the images stored in earlier blocks, as images
+/// which duplicate them are stored as duplicates too.
+pub fn plan_bidirectional_frames<I>(images : I, stored_hashes : &HashMap<[u8; 32], usize>, settings : &CompressionSettings) -> Result<Vec<(usize, bool)>, String>
+where I : Iterator<Item = image::RgbaImage>
+{
+    let mut images = images.enumerate().peekable();
+    //every image stored so far, in this block or an earlier one
+    let mut image_hashes : HashSet<[u8; 32]> = stored_hashes.keys().cloned().collect();
+    let mut plan = Vec::new();
+    //the last image stored, which the next one is predicted from (there is none at the start of a block)
+    let mut prev_image : Option<image::RgbaImage> = None;
 
-        if pixels_equal {
-            difference.push(0u8);
-            debug_difference_count += 1;
+    while let Some((i, image)) = images.next() {
+        if !image_hashes.insert(hash_image(&image)) {
+            plan.push((i, false));
+            continue;
+        }
+
+        let mut use_b_frame = false;
+        if let (Some(prev_image), Some((_, next_image))) = (&prev_image, images.peek()) {
+            if !image_hashes.contains(&hash_image(next_image)) {
+                let stored_size = estimate_stored_size(settings, &image, prev_image)? + estimate_stored_size(settings, next_image, &image)?;
+                let b_frame_size = estimate_stored_size(settings, next_image, prev_image)? +
+                    estimate_encoded_size(&BIDIRECTIONAL_STRATEGY, &image, prev_image, Some(next_image), settings.scan_order);
+                use_b_frame = b_frame_size < stored_size;
+            }
+        }
+
+        if use_b_frame {
+            let (next_i, next_image) = images.next().unwrap();
+            image_hashes.insert(hash_image(&next_image));
+            plan.push((next_i, false));
+            plan.push((i, true));
+            prev_image = Some(next_image);
         }
         else {
-            difference.push(1u8);
-            image_compressor.write(&cropped_pixel.0).unwrap();
+            plan.push((i, false));
+            prev_image = Some(image);
         }
     }
+    Ok(plan)
+}
 
-    bitmap_compressor.write(&difference).unwrap();
+/// The strategy to encode image with - the one given in settings, or the best one if auto_strategy is set
+pub fn pick_strategy(settings : &CompressionSettings, image : &image::RgbaImage, reference_image : &image::RgbaImage) -> Result<&'static dyn DiffStrategy, String>
+{
+    if settings.auto_strategy {
+        Ok(choose_strategy(image, reference_image, settings.scan_order))
+    }
+    else {
+        let strategy = strategy_by_id(settings.strategy)?;
+        if strategy.is_bidirectional() {
+            return Err(format!("The {} strategy is only used for the images picked by the bidirectional search", strategy.name()));
+        }
+        Ok(strategy)
+    }
+}
 
-    //return crop_region to be saved as metadata
-    return crop_region
+/// Encodes one image with the strategy, printing how different it was from the reference
+pub fn alt_compression_3_inner(original_image : &image::RgbaImage, prev_image : &image::RgbaImage, next_image : Option<&image::RgbaImage>, strategy : &dyn DiffStrategy, scan_order : ScanOrder, image_compressor : &mut dyn std::io::Write, bitmap_compressor : &mut dyn std::io::Write) -> CropRegion
+{
+    let (crop_region, difference_count) = strategy.encode(original_image, prev_image, next_image, scan_order, image_compressor, bitmap_compressor);
+    print!("Diff: {} ", pretty_print_percent(difference_count, original_image.width() as u64 * original_image.height() as u64));
+    print!("CropTL: ({:4},{:4}) ", crop_region.top_left.0, crop_region.top_left.1);
+    print!("CropSize: ({:4},{:4}) ", crop_region.dimensions.0, crop_region.dimensions.1);
+    crop_region
 }
 
 //new image format:
@@ -356,6 +707,19 @@ where T: std::io::Write,
 //[brotli compressed image Rgba<u8>]    compressed_image_data_ALL         - uncompressed size is equal to the number of '1's in the bitmap
 //[brotli compressed imageS metadata]   compressed_image_metadata_1       - use bincode::deserialize_from to get struct out of compressed data.
 
+//format4 (current) - format3, but the image data/bitmap streams are split into solid blocks, and each
+//                    stream can use a different codec (brotli/zstd/xz)
+// format                              data name                       description
+//---------------------------------------------------------------------------------------------------
+//[32 bytes]                            ArchiveHeader                   - see common.rs, holds metadata_start_index, the codec of each stream and the scan order
+//[compressed image Rgba<u8>]           block_0_image_data              - first image of each block is diffed against the null image
+//[compressed Vec<u8>]                  block_0_bitmap
+//                                      block_1_image_data
+//                                      block_1_bitmap
+// ...more blocks go here...
+//[compressed DecompressionInfo]        metadata                        - SolidBlockInfo gives the file offsets of each block's streams
+//[optional parity section]            parity                          - Reed-Solomon parity over everything above, see parity.rs
+
 // ...more images go here...
 // to ensure compression effiency:
 // For this method, should make the big image data backed by a file, and the remaining two backed by Vec<u8> (they should be small, even with 10,000 images. Should print this out to check size
@@ -364,8 +728,7 @@ where T: std::io::Write,
 //
 
 
-//iteration order should be blockwise, optionally pixels should be scanned in snake order/blocks
-//also scanned in snake order.
+//iteration order is blockwise by default, see scan_order.rs for the others
 
 //before first iteration,
 // - init compressor object
@@ -390,3 +753,291 @@ where T: std::io::Write,
     //compress the difference map
 
     //prev_image = image
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::Cursor;
+
+    use image::{Rgba, RgbaImage};
+    use proptest::prelude::*;
+    use proptest::collection::vec;
+
+    use codec::{Codec, CodecSettings};
+    use common::ScratchFolder;
+    use reader::ArchiveReader;
+    use stats::CompressionStats;
+    use validate::ArchiveLimits;
+    use scan_order::{ScanOrder, DEFAULT_SCAN_ORDER};
+    use strategy::{DiffStrategy, BASELINE_STRATEGY, STRATEGIES};
+    use super::{alt_compression_2, alt_compression_3_inner, plan_bidirectional_frames, CompressionSettings, SolidBlockLimits};
+
+    //opaque and translucent colours, plus two invisible pixels with different colour values (which must not be merged)
+    const PALETTE : [[u8; 4]; 6] = [[0, 0, 0, 0], [7, 7, 7, 0], [255, 0, 0, 255], [0, 200, 50, 255], [30, 60, 90, 128], [255, 255, 255, 255]];
+
+    /// Where a sprite is drawn on its canvas
+    #[derive(Debug, Clone)]
+    enum Placement {
+        //how sprite sheets are usually aligned, and how the compressor lines up consecutive images
+        BottomCenter,
+        //anywhere else on the canvas (wrapped to fit)
+        At(u32, u32),
+    }
+
+    /// One frame of a synthetic sprite sequence
+    #[derive(Debug, Clone)]
+    enum Frame {
+        //the sprite with a few pixels changed, on a canvas with a transparent border
+        Sprite { border : (u32, u32), placement : Placement, edits : Vec<(u32, u32, usize)> },
+        //exactly the previous frame again, so the diff is zero sized
+        Repeat,
+        //the previous frame mirrored, so a reference transform is chosen
+        Mirrored,
+        //a 1x1 image
+        Single(usize),
+        //a copy of an earlier frame (wrapped to the frames so far), which may be in an earlier solid block
+        Copy(usize),
+    }
+
+    fn render_frames(sprite : &RgbaImage, frames : &[Frame]) -> Vec<RgbaImage>
+    {
+        let mut images : Vec<RgbaImage> = Vec::new();
+        for frame in frames {
+            let image = match frame {
+                Frame::Sprite { border, placement, edits } => {
+                    let mut edited_sprite = sprite.clone();
+                    for &(x, y, colour) in edits {
+                        edited_sprite.put_pixel(x % sprite.width(), y % sprite.height(), Rgba(PALETTE[colour]));
+                    }
+
+                    let (x, y) = match placement {
+                        Placement::BottomCenter => (border.0 / 2, border.1),
+                        Placement::At(x, y) => (x % (border.0 + 1), y % (border.1 + 1)),
+                    };
+                    let mut canvas = RgbaImage::new(sprite.width() + border.0, sprite.height() + border.1);
+                    image::imageops::replace(&mut canvas, &edited_sprite, x as i64, y as i64);
+                    canvas
+                },
+                Frame::Repeat => images.last().cloned().unwrap_or_else(|| sprite.clone()),
+                Frame::Mirrored => image::imageops::flip_horizontal(images.last().unwrap_or(sprite)),
+                Frame::Single(colour) => RgbaImage::from_pixel(1, 1, Rgba(PALETTE[*colour])),
+                Frame::Copy(index) => if images.is_empty() { sprite.clone() } else { images[index % images.len()].clone() },
+            };
+            images.push(image);
+        }
+        images
+    }
+
+    /// Low quality settings - the tests check correctness, not compression ratio
+    fn fast_codec_settings(codec : Codec) -> CodecSettings
+    {
+        let quality = match codec {
+            Codec::Brotli => 5,
+            Codec::Zstd => 3,
+            Codec::Xz => 1,
+        };
+        CodecSettings { quality, window : 20, ..codec.default_settings() }
+    }
+
+    /// Settings with fast_codec_settings for every stream, and the defaults for everything else
+    pub(crate) fn fast_settings(codec : Codec) -> CompressionSettings
+    {
+        let codec_settings = fast_codec_settings(codec);
+        CompressionSettings { image_codec : codec_settings, bitmap_codec : codec_settings, metadata_codec : codec_settings, ..CompressionSettings::default() }
+    }
+
+    /// Saves the images as 000.png, 001.png... in a scratch folder, and compresses them with alt_compression_2,
+    /// returning the archive and the compression statistics
+    pub(crate) fn compress_to_archive(images : &[RgbaImage], settings : &CompressionSettings) -> (Vec<u8>, CompressionStats)
+    {
+        let scratch_folder = ScratchFolder::new().unwrap();
+        let input_folder = scratch_folder.path.join("input_images");
+        fs::create_dir(&input_folder).unwrap();
+        for (i, image) in images.iter().enumerate() {
+            image.save(input_folder.join(format!("{:03}.png", i))).unwrap();
+        }
+
+        let archive_path = scratch_folder.path.join("compressed_images.brotli");
+        let stats = alt_compression_2(input_folder.to_str().unwrap(), archive_path.to_str().unwrap(), settings).unwrap();
+        (fs::read(&archive_path).unwrap(), stats)
+    }
+
+    /// The images in the archive, in the order they were given to compress_to_archive
+    fn extract_from_archive(archive : Vec<u8>) -> Vec<RgbaImage>
+    {
+        let reader = ArchiveReader::new(Cursor::new(archive), &ArchiveLimits::default()).unwrap();
+        let images_info = &reader.decompression_info.images_info;
+        let mut extracted : Vec<Option<RgbaImage>> = vec![None; images_info.len()];
+        for block_i in 0..reader.decompression_info.blocks.len() {
+            let block_data = reader.read_solid_block(block_i).unwrap();
+            reader.decode_solid_block(block_i, &block_data, false, |img_i, image| extracted[img_i] = Some(image.clone())).unwrap();
+        }
+        for (img_i, metadata) in images_info.iter().enumerate() {
+            if let Some(original_index) = metadata.duplicate_of {
+                extracted[img_i] = extracted[original_index].clone();
+            }
+        }
+
+        //the images are named after their index
+        let mut extracted : Vec<(usize, RgbaImage)> = extracted.into_iter().enumerate()
+            .map(|(img_i, image)| (images_info[img_i].output_path.trim_end_matches(".png").parse::<usize>().unwrap(), image.unwrap()))
+            .collect();
+        extracted.sort_by_key(|(i, _)| *i);
+        extracted.into_iter().map(|(_, image)| image).collect()
+    }
+
+    /// Compresses the images with alt_compression_2 and checks that every image extracts exactly, returning the
+    /// compression statistics
+    fn assert_round_trip(images : &[RgbaImage], settings : &CompressionSettings) -> CompressionStats
+    {
+        let (archive, stats) = compress_to_archive(images, settings);
+        let extracted = extract_from_archive(archive);
+        assert_eq!(extracted.len(), images.len());
+        for (i, (original, extracted)) in images.iter().zip(extracted.iter()).enumerate() {
+            assert_eq!(original.dimensions(), extracted.dimensions(), "image {} has the wrong size", i);
+            assert!(original.as_raw() == extracted.as_raw(), "image {} has different pixels", i);
+        }
+        stats
+    }
+
+    fn sprite() -> impl Strategy<Value = RgbaImage>
+    {
+        (1u32..60, 1u32..60).prop_flat_map(|(width, height)| {
+            vec(0..PALETTE.len(), (width * height) as usize).prop_map(move |pixels| {
+                RgbaImage::from_fn(width, height, |x, y| Rgba(PALETTE[pixels[(y * width + x) as usize]]))
+            })
+        })
+    }
+
+    fn frame() -> impl Strategy<Value = Frame>
+    {
+        let placement = prop_oneof![
+            Just(Placement::BottomCenter),
+            (0u32..64, 0u32..64).prop_map(|(x, y)| Placement::At(x, y)),
+        ];
+        let edits = vec((0u32..64, 0u32..64, 0..PALETTE.len()), 0..8);
+
+        prop_oneof![
+            4 => ((0u32..16, 0u32..16), placement, edits).prop_map(|(border, placement, edits)| Frame::Sprite { border, placement, edits }),
+            1 => Just(Frame::Repeat),
+            1 => Just(Frame::Mirrored),
+            1 => (0..PALETTE.len()).prop_map(Frame::Single),
+            1 => (0usize..8).prop_map(Frame::Copy),
+        ]
+    }
+
+    /// Any strategy which can be used for every image
+    fn strategy_id() -> impl Strategy<Value = u8>
+    {
+        let ids : Vec<u8> = STRATEGIES.iter().filter(|strategy| !strategy.is_bidirectional()).map(|strategy| strategy.id()).collect();
+        proptest::sample::select(ids)
+    }
+
+    fn codec() -> impl Strategy<Value = Codec>
+    {
+        prop_oneof![Just(Codec::Brotli), Just(Codec::Zstd), Just(Codec::Xz)]
+    }
+
+    fn scan_order() -> impl Strategy<Value = ScanOrder>
+    {
+        prop_oneof![
+            Just(DEFAULT_SCAN_ORDER),
+            (1u16..20).prop_map(ScanOrder::Blocks),
+            Just(ScanOrder::Rows),
+            Just(ScanOrder::Snake),
+            (1u16..20).prop_map(ScanOrder::SnakeBlocks),
+            Just(ScanOrder::Hilbert),
+            Just(ScanOrder::ZOrder),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn sprite_sequences_round_trip(sprite in sprite(), frames in vec(frame(), 1..8), codec in codec())
+        {
+            assert_round_trip(&render_frames(&sprite, &frames), &fast_settings(codec));
+        }
+
+        #[test]
+        fn every_setting_round_trips(sprite in sprite(), frames in vec(frame(), 1..7), strategy_id in proptest::option::of(strategy_id()), scan_order in scan_order(),
+                                     context_coded_bitmap in any::<bool>(), bidirectional in any::<bool>(), block_images in proptest::option::of(1usize..4))
+        {
+            let settings = CompressionSettings {
+                block_limits : SolidBlockLimits { max_images : block_images, max_uncompressed_bytes : None },
+                //None lets choose_strategy pick for each image
+                auto_strategy : strategy_id.is_none(),
+                strategy : strategy_id.unwrap_or(BASELINE_STRATEGY.id()),
+                scan_order,
+                context_coded_bitmap,
+                bidirectional,
+                ..fast_settings(Codec::Brotli)
+            };
+            assert_round_trip(&render_frames(&sprite, &frames), &settings);
+        }
+    }
+
+    #[test]
+    fn single_pixel_images_round_trip()
+    {
+        let images : Vec<RgbaImage> = PALETTE.iter().map(|&colour| RgbaImage::from_pixel(1, 1, Rgba(colour))).collect();
+        assert_round_trip(&images, &fast_settings(Codec::Brotli));
+    }
+
+    #[test]
+    fn identical_frames_have_zero_size_diff()
+    {
+        let image = RgbaImage::from_fn(70, 30, |x, y| Rgba(PALETTE[((x * 7 + y * 3) % 6) as usize]));
+
+        let mut image_data = Vec::new();
+        let mut bitmap = Vec::new();
+        let crop_region = alt_compression_3_inner(&image, &image, None, &BASELINE_STRATEGY, DEFAULT_SCAN_ORDER, &mut image_data, &mut bitmap);
+        assert_eq!(crop_region.dimensions, (0, 0));
+        assert!(image_data.is_empty() && bitmap.is_empty());
+
+        //the copies are stored as duplicates
+        let stats = assert_round_trip(&[image.clone(), image.clone(), image], &fast_settings(Codec::Brotli));
+        assert_eq!(stats.images.iter().filter(|image_stats| image_stats.duplicate_of.is_some()).count(), 2);
+        let estimates : Vec<u64> = stats.images.iter().map(|image_stats| image_stats.estimated_compressed_bytes).collect();
+        assert!(estimates[0] > 0 && estimates[1..] == [0, 0]);
+    }
+
+    /// Eyes open, closed, then open again (with one other pixel changed, so it isn't a duplicate). The open eyes are
+    /// the same for every variant, the closed eyes and the changed pixel differ.
+    fn blink(variant : u32) -> Vec<RgbaImage>
+    {
+        let noise = |x : u32, y : u32, seed : u32| PALETTE[((x.wrapping_mul(7919) ^ y.wrapping_mul(104729) ^ seed).wrapping_mul(2654435761) >> 29) as usize % PALETTE.len()];
+        let open = RgbaImage::from_fn(32, 32, |x, y| Rgba(noise(x, y, 1)));
+        let closed = RgbaImage::from_fn(32, 32, |x, y| Rgba(if (10..22).contains(&x) && (10..22).contains(&y) { noise(x, y, 2 + variant) } else { noise(x, y, 1) }));
+        let mut open_again = open.clone();
+        open_again.put_pixel(variant, 0, Rgba(PALETTE[(PALETTE.iter().position(|&colour| colour == open.get_pixel(variant, 0).0).unwrap() + 1) % PALETTE.len()]));
+        vec![open, closed, open_again]
+    }
+
+    #[test]
+    fn blink_is_stored_as_b_frame()
+    {
+        //the closed eyes are only needed once as a B-frame, instead of being stored and then undone
+        let plan = plan_bidirectional_frames(blink(0).into_iter(), &HashMap::new(), &CompressionSettings::default()).unwrap();
+        assert_eq!(plan, vec![(0, false), (2, false), (1, true)]);
+    }
+
+    #[test]
+    fn b_frames_are_only_predicted_from_stored_images()
+    {
+        //the second block starts with a duplicate of the first block's first image, which isn't stored in the second
+        //block, so its B-frame would only have one image to be predicted from
+        let images : Vec<RgbaImage> = blink(0).into_iter().chain(blink(1)).collect();
+        let settings = CompressionSettings {
+            block_limits : SolidBlockLimits { max_images : Some(3), ..SolidBlockLimits::default() },
+            bidirectional : true,
+            ..fast_settings(Codec::Brotli)
+        };
+
+        let stats = assert_round_trip(&images, &settings);
+        let duplicates : Vec<&str> = stats.images.iter().filter(|image_stats| image_stats.duplicate_of.is_some()).map(|image_stats| image_stats.path.as_str()).collect();
+        let b_frames : Vec<&str> = stats.images.iter().filter(|image_stats| image_stats.strategy == "bidirectional").map(|image_stats| image_stats.path.as_str()).collect();
+        assert_eq!(duplicates, ["003.png"]);
+        assert_eq!(b_frames, ["001.png"]);
+    }
+}
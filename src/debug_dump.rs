@@ -0,0 +1,195 @@
+//standard uses
+use std;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+//non-standard use
+use image;
+use image::{Rgba, RgbaImage};
+use walkdir;
+
+use common::FileTypeIterator;
+use common::{get_offset_to_other_image, try_get_pixel, hash_image, remove_alpha};
+use common::ReferenceTransform;
+use compress::{CompressionSettings, CropRegion};
+use compress::{alt_compression_3_inner, choose_reference_transform, pick_strategy, split_into_solid_blocks};
+use scan_order::ScanOrder;
+use strategy::DiffStrategy;
+
+/// How often each pixel changed, over every image in one folder. The images are lined up the same way the
+/// compressor lines up consecutive images (centered horizontally, aligned to the bottom).
+struct Heatmap {
+    //(width, height, changed) of every image added so far - the canvas size isn't known until all are added
+    images : Vec<(u32, u32, Vec<bool>)>,
+}
+
+impl Heatmap {
+    fn add_image(&mut self, image : &RgbaImage, reference_image : &RgbaImage)
+    {
+        let (x_offset_to_reference, y_offset_to_reference) = get_offset_to_other_image(image, reference_image);
+        let changed = image.enumerate_pixels()
+            .map(|(x, y, pixel)| try_get_pixel((x as i64 + x_offset_to_reference, y as i64 + y_offset_to_reference), reference_image) != Some(*pixel))
+            .collect();
+        self.images.push((image.width(), image.height(), changed));
+    }
+
+    /// Black where nothing changed, through red and yellow to white where the most changes were
+    fn render(&self) -> RgbaImage
+    {
+        let canvas_width = self.images.iter().map(|&(width, _, _)| width).max().unwrap_or(0);
+        let canvas_height = self.images.iter().map(|&(_, height, _)| height).max().unwrap_or(0);
+
+        let mut counts = vec![0u32; canvas_width as usize * canvas_height as usize];
+        for (width, height, changed) in &self.images {
+            let (x0, y0) = ((canvas_width - width) / 2, canvas_height - height);
+            for (i, _) in changed.iter().enumerate().filter(|&(_, &changed)| changed) {
+                let (x, y) = (x0 + i as u32 % width, y0 + i as u32 / width);
+                counts[(y * canvas_width + x) as usize] += 1;
+            }
+        }
+
+        let max_count = std::cmp::max(1, counts.iter().cloned().max().unwrap_or(0));
+        RgbaImage::from_fn(canvas_width, canvas_height, |x, y| {
+            let heat = counts[(y * canvas_width + x) as usize] as f64 / max_count as f64 * 3.0;
+            let channel = |offset : f64| ((heat - offset).clamp(0.0, 1.0) * 255.0) as u8;
+            Rgba([channel(0.0), channel(1.0), channel(2.0), 255])
+        })
+    }
+}
+
+/// Shows how the image lines up with its reference - the image's brightness is drawn in red and the reference's
+/// in cyan, so identical areas are gray and differences are coloured. The diff rectangle is outlined in yellow.
+fn render_overlay(image : &RgbaImage, reference_image : &RgbaImage, crop_region : &CropRegion) -> RgbaImage
+{
+    let brightness = |pixel : &Rgba<u8>| ((pixel[0] as u32 * 3 + pixel[1] as u32 * 6 + pixel[2] as u32) / 10 * pixel[3] as u32 / 255) as u8;
+    let (x_offset_to_reference, y_offset_to_reference) = get_offset_to_other_image(image, reference_image);
+
+    let mut overlay = RgbaImage::from_fn(image.width(), image.height(), |x, y| {
+        let image_brightness = brightness(image.get_pixel(x, y));
+        let reference_brightness = try_get_pixel((x as i64 + x_offset_to_reference, y as i64 + y_offset_to_reference), reference_image)
+            .map_or(0, |pixel| brightness(&pixel));
+        Rgba([image_brightness, reference_brightness, reference_brightness, 255])
+    });
+
+    let (left, top) = crop_region.top_left;
+    let (width, height) = crop_region.dimensions;
+    if width > 0 && height > 0 {
+        for x in left..left + width {
+            overlay.put_pixel(x, top, Rgba([255, 255, 0, 255]));
+            overlay.put_pixel(x, top + height - 1, Rgba([255, 255, 0, 255]));
+        }
+        for y in top..top + height {
+            overlay.put_pixel(left, y, Rgba([255, 255, 0, 255]));
+            overlay.put_pixel(left + width - 1, y, Rgba([255, 255, 0, 255]));
+        }
+    }
+
+    overlay
+}
+
+/// Lays the 0/1 bitmap out the way the extractor reads it (in the strategy's scan order), white for changed pixels
+fn render_bitmap(bitmap : &[u8], dimensions : (u32, u32), strategy : &dyn DiffStrategy, scan_order : ScanOrder) -> RgbaImage
+{
+    let mut bitmap_image = RgbaImage::from_pixel(dimensions.0, dimensions.1, Rgba([0, 0, 0, 255]));
+    let diff_xy_iter = strategy.scan_order(scan_order, dimensions);
+    for ((x, y), &bitmap_value) in diff_xy_iter.zip(bitmap.iter()) {
+        if bitmap_value == 1 {
+            bitmap_image.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+        }
+    }
+    bitmap_image
+}
+
+/// Saves the image, unless it is empty (a png can't have a zero width or height)
+fn save_debug_image(image : &RgbaImage, path : &Path) -> Result<(), String>
+{
+    if image.width() > 0 && image.height() > 0 {
+        image.save(path).map_err(|e| format!("Unable to save {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+/// Runs the compressor over the input folder without writing an archive, and saves what it sees for each image into
+/// a folder (named after the image) in dump_folder:
+/// - reference.png: the previous image, after any flip/rotation, which the image is diffed against (alpha ignored)
+/// - overlay.png: the image and reference lined up, with the diff rectangle outlined (see render_overlay)
+/// - diff_crop.png: the part of the image inside the diff rectangle, which is all that gets stored
+/// - bitmap.png: the 0/1 difference bitmap for the diff rectangle, white where a pixel is stored
+///
+/// A heatmap of how often each pixel changes is also saved for each folder of images.
+/// Returns the number of images dumped.
+pub fn debug_dump(input_folder : &str, dump_folder : &str, settings : &CompressionSettings) -> Result<usize, String>
+{
+    let image_entries : Vec<walkdir::DirEntry> = FileTypeIterator::new(input_folder, "png").collect();
+    if image_entries.is_empty() {
+        return Err(format!("No .png images found in '{}'", input_folder));
+    }
+
+    let mut heatmaps : BTreeMap<PathBuf, Heatmap> = BTreeMap::new();
+    let mut image_hashes : HashMap<[u8; 32], PathBuf> = HashMap::new();
+    let mut num_dumped = 0;
+
+    //mirror the compressor - each solid block starts from the null image, and duplicates don't change the reference
+    for block_entries in split_into_solid_blocks(&image_entries, &settings.block_limits)
+    {
+        let mut prev_image = RgbaImage::new(0, 0);
+        for ent in block_entries
+        {
+            let path_relative_to_input_folder = ent.path().strip_prefix(input_folder).unwrap();
+            let image = image::open(ent.path()).map_err(|e| format!("Unable to open {}: {}", ent.path().display(), e))?.to_rgba8();
+
+            if let Some(original_path) = image_hashes.get(&hash_image(&image)) {
+                println!("{}: duplicate of {}, nothing to dump", path_relative_to_input_folder.display(), original_path.display());
+                continue;
+            }
+            image_hashes.insert(hash_image(&image), path_relative_to_input_folder.to_path_buf());
+
+            let (reference_transform, mut reference_image) = choose_reference_transform(&image, &prev_image);
+            let strategy = pick_strategy(settings, &image, &reference_image)?;
+            if !strategy.uses_reference() {
+                reference_image = RgbaImage::new(0, 0);
+            }
+            else if reference_transform != ReferenceTransform::None {
+                print!("Ref: {:?} ", reference_transform);
+            }
+            if settings.auto_strategy {
+                print!("Strategy: {:12} ", strategy.name());
+            }
+
+            let mut bitmap = Vec::new();
+            let crop_region = alt_compression_3_inner(&image, &reference_image, None, strategy, settings.scan_order, &mut io::sink(), &mut bitmap);
+            println!("{}", path_relative_to_input_folder.display());
+
+            let image_dump_folder = Path::new(dump_folder).join(path_relative_to_input_folder.with_extension(""));
+            fs::create_dir_all(&image_dump_folder).map_err(|e| format!("Unable to create {}: {}", image_dump_folder.display(), e))?;
+
+            let mut reference_no_alpha = reference_image.clone();
+            remove_alpha(&mut reference_no_alpha);
+            let (left, top) = crop_region.top_left;
+            let (width, height) = crop_region.dimensions;
+            let diff_crop = image::imageops::crop_imm(&image, left, top, width, height).to_image();
+
+            save_debug_image(&reference_no_alpha, &image_dump_folder.join("reference.png"))?;
+            save_debug_image(&render_overlay(&image, &reference_image, &crop_region), &image_dump_folder.join("overlay.png"))?;
+            save_debug_image(&diff_crop, &image_dump_folder.join("diff_crop.png"))?;
+            save_debug_image(&render_bitmap(&bitmap, crop_region.dimensions, strategy, settings.scan_order), &image_dump_folder.join("bitmap.png"))?;
+
+            let set_folder = path_relative_to_input_folder.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+            heatmaps.entry(set_folder).or_insert_with(|| Heatmap { images : Vec::new() }).add_image(&image, &reference_image);
+
+            prev_image = image;
+            num_dumped += 1;
+        }
+    }
+
+    for (set_folder, heatmap) in &heatmaps {
+        let heatmap_path = Path::new(dump_folder).join(set_folder).join("heatmap.png");
+        fs::create_dir_all(heatmap_path.parent().unwrap()).map_err(|e| format!("Unable to create {}: {}", heatmap_path.display(), e))?;
+        println!("Saving heatmap of {} images to {}", heatmap.images.len(), heatmap_path.display());
+        save_debug_image(&heatmap.render(), &heatmap_path)?;
+    }
+
+    Ok(num_dumped)
+}
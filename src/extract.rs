@@ -1,144 +1,481 @@
 use std;
 use std::fs;
-use std::path::Path;
-use std::io::{Read, SeekFrom, Seek};
+use std::path::{Path, PathBuf};
+use std::io::BufReader;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
 
-use brotli;
-use bincode;
 use image;
 use image::{RgbaImage};
 use oxipng;
 use png;
 
 use common::{pretty_print_bytes};
-use common::{DecompressionInfo};
-use common::u8_buf_to_u64_little_endian;
-use common::{FILE_FORMAT_HEADER_LENGTH, BROTLI_BUFFER_SIZE};
-use common::get_offset_to_other_image;
-use common::BlockXYIterator;
-use common::try_get_pixel;
-
-pub fn extract_archive_alt(brotli_archive_path : &str, oxipng_options : Option<oxipng::Options>, debug_mode : bool) {
-    //open the brotli file for reading
-    let mut brotli_file = fs::File::open(brotli_archive_path).unwrap();
-
-    //determine where the decompression info starts
-    let mut header : [u8; FILE_FORMAT_HEADER_LENGTH] = [0; FILE_FORMAT_HEADER_LENGTH];
-    brotli_file.read(&mut header).expect("Unable to read brotli file header");
-    let decompression_info_start = u8_buf_to_u64_little_endian(&header);
-
-    //Skip to the decompression information section, and deserialize
-    let debug_metadata_start = brotli_file.seek(SeekFrom::Start(decompression_info_start)).unwrap();
-    let decompression_info : DecompressionInfo = {
-        let mut decompression_info_decompressor = brotli::Decompressor::new(&brotli_file, BROTLI_BUFFER_SIZE);
-        let mut raw_decompression_info = Vec::new();
-        decompression_info_decompressor.read_to_end(&mut raw_decompression_info).unwrap();
-        bincode::deserialize(&raw_decompression_info).unwrap()
-    };
-
-    //fully read the compressed bitmap info into memory (theoretically can be avoided, but just do it this way for now...)
-    //the data starts at decompression_info.bitmap_data_start and ends at (decompression_info_start-1)
-    let mut compressed_bitmap = vec![0u8; decompression_info_start as usize - decompression_info.bitmap_data_start as usize];
-    let debug_bitmap_start = brotli_file.seek(SeekFrom::Start(decompression_info.bitmap_data_start)).unwrap();
-    brotli_file.read_exact(&mut compressed_bitmap).unwrap();
-    let mut bitmap_info_decompressor = brotli::Decompressor::new(&compressed_bitmap[..], BROTLI_BUFFER_SIZE);
-
-    //Skip to the brotli compressed data section, then begin extraction
-    let debug_image_data_start = brotli_file.seek(SeekFrom::Start(FILE_FORMAT_HEADER_LENGTH as u64)).unwrap();
-    let mut image_data_decompressor = brotli::Decompressor::new(brotli_file, BROTLI_BUFFER_SIZE);
-
-    println!("Brotli compressed data starts at {} ({}) [size: {}]",
-             debug_image_data_start,
-             pretty_print_bytes(debug_image_data_start as f64),
-             pretty_print_bytes(debug_image_data_start as f64));
-    println!("Bitmap information starts at {} ({}) [size: {}]",
-             debug_bitmap_start,
-             pretty_print_bytes(debug_bitmap_start as f64),
-             pretty_print_bytes((debug_bitmap_start - debug_image_data_start) as f64));
-    println!("Decompression information starts at {} ({}) [size: {}]",
-             debug_metadata_start,
-             pretty_print_bytes(debug_metadata_start as f64),
-             pretty_print_bytes((debug_metadata_start - debug_bitmap_start) as f64),
-    );
+use common::DecompressionInfo;
+use common::sanitize_archive_path;
+use common::hash_image;
+use reader::ArchiveReader;
+use validate::ArchiveLimits;
+
+/// What to do when an image being extracted already exists in the output folder
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverwritePolicy {
+    //replace existing files
+    #[default]
+    Overwrite,
+    //leave existing files alone (the images are still rebuilt, as later images are diffed against them)
+    SkipExisting,
+    //refuse to extract anything if any output file already exists
+    FailIfExists,
+    //leave existing files alone if they already hold exactly the rebuilt image, otherwise replace them.
+    //used to continue an extraction which was interrupted
+    Resume,
+}
+
+/// All user configurable extraction settings
+#[derive(Debug, Clone, Default)]
+pub struct ExtractionSettings {
+    //if set, .png files are optimized with oxipng before saving
+    pub oxipng_options : Option<oxipng::Options>,
+    pub debug_mode : bool,
+    //create duplicate images as hardlinks to the first copy, instead of copying the file
+    pub hardlink_duplicates : bool,
+    //use the paths stored in the archive as-is, even if they are absolute or contain '..'
+    //only use this for archives you trust!
+    pub allow_unsafe_paths : bool,
+    //refuse archives with larger images/metadata than this
+    pub limits : ArchiveLimits,
+    //keep going when a solid block is damaged, saving every image which could still be rebuilt
+    pub salvage : bool,
+    pub overwrite_policy : OverwritePolicy,
+}
+
+/// Works out where each image in the archive will be written. Unless unsafe paths are allowed,
+/// every stored path is checked so that nothing can be written outside the output folder.
+fn get_output_paths(decompression_info : &DecompressionInfo, output_folder : &str, allow_unsafe_paths : bool) -> Result<Vec<PathBuf>, String>
+{
+    decompression_info.images_info.iter().map(|metadata| {
+        if allow_unsafe_paths {
+            Ok(Path::new(output_folder).join(&metadata.output_path))
+        }
+        else {
+            sanitize_archive_path(&metadata.output_path)
+                .map(|path| Path::new(output_folder).join(path))
+                .map_err(|e| format!("{} - refusing to extract (use --allow-unsafe-paths if you trust this archive)", e))
+        }
+    }).collect()
+}
+
+pub fn extract_archive_alt(brotli_archive_path : &str, output_folder : &str, settings : &ExtractionSettings) -> Result<(), String> {
+    //open the brotli file for reading, and read/check the header and metadata
+    let brotli_file = fs::File::open(brotli_archive_path).map_err(|e| format!("Unable to open archive: {}", e))?;
+    let archive = ArchiveReader::new(BufReader::new(brotli_file), &settings.limits)?;
+    let header = &archive.header;
+    let decompression_info = &archive.decompression_info;
+    let bitmap_codec_name = if header.context_coded_bitmap { "context coded" } else { header.bitmap_codec.name() };
+    println!("Codecs - image data: {}, bitmap: {}, metadata: {}", header.image_codec.name(), bitmap_codec_name, header.metadata_codec.name());
+    println!("Scan order: {}", header.scan_order);
+
+    //check where every image will be written before extracting anything
+    let output_paths = get_output_paths(decompression_info, output_folder, settings.allow_unsafe_paths)?;
+    if settings.overwrite_policy == OverwritePolicy::FailIfExists {
+        if let Some(existing_path) = output_paths.iter().find(|path| path.exists()) {
+            return Err(format!("{} already exists - refusing to extract (use --overwrite, --skip-existing or --resume)", existing_path.display()));
+        }
+    }
+
+    for (block_i, block) in decompression_info.blocks.iter().enumerate() {
+        println!("Solid block {}: {} images, image data at {} [size: {}], bitmap at {} [size: {}]",
+                 block_i,
+                 block.num_images,
+                 block.image_data_start,
+                 pretty_print_bytes((block.bitmap_data_start - block.image_data_start) as f64),
+                 block.bitmap_data_start,
+                 pretty_print_bytes((block.block_end - block.bitmap_data_start) as f64));
+    }
+    println!("Decompression information starts at {} ({})",
+             header.metadata_start,
+             pretty_print_bytes(header.metadata_start as f64));
+    if header.parity_start != 0 {
+        println!("Archive has parity data at {} (use 'spritezip repair' if it is damaged)", header.parity_start);
+    }
     println!("\n\n --------- Preparation Complete. Extracting Images ----------");
 
-    //for each image
-    let mut prev_image = RgbaImage::new(0,0); //on first image iteration, this should never get accessed
-    let num_images = decompression_info.images_info.len();
-    for (img_i, metadata) in decompression_info.images_info.into_iter().enumerate()
-    {
-        print!("{}/{}: ", img_i + 1, num_images);
-        print!("diff: ({:4},{:4}) ", metadata.diff_width, metadata.diff_height);
-        print!("full: ({:4},{:4}) ", metadata.output_width, metadata.output_height);
-        print!("{}", metadata.output_path);
-        println!("");
-
-        //take a slice which contains only the desired region
-        //read out the required number of bytes
-        let expected_cropped_bitmap_size = metadata.diff_width as usize * metadata.diff_height as usize;
-        if debug_mode { println!("meta: {:?}", metadata); println!("Trying to extract {} bytes of bitmap data", expected_cropped_bitmap_size)}
-
-        let mut cropped_bitmap = vec![0u8; expected_cropped_bitmap_size];
-        bitmap_info_decompressor.read_exact(&mut cropped_bitmap).unwrap();
-
-        //reconstruct the image
-        let mut full_image = RgbaImage::new(metadata.output_width, metadata.output_height);
-
-        let (x_offset_to_prev_image , y_offset_to_prev_image)= get_offset_to_other_image(&full_image, &prev_image);
-        if debug_mode { println!("Offset to other image: ({},{})", x_offset_to_prev_image , y_offset_to_prev_image); }
-
-        //copy over the original image TODO: find a better way to do this?
-        for y in 0..full_image.height() {
-            for x in 0..full_image.width() {
-                match try_get_pixel((x as i64 + x_offset_to_prev_image, y as i64 + y_offset_to_prev_image), &prev_image) {
-                    None => {}
-                    Some(pixel) => *full_image.get_pixel_mut(x, y) = pixel
+    //png encoding/oxipng is much slower than rebuilding the images, so it is done on other threads
+    let save_pool = SaveWorkerPool::new(settings.oxipng_options.clone(), settings.overwrite_policy);
+
+    //each solid block starts with a keyframe, so the blocks are rebuilt concurrently
+    let num_decoders = std::cmp::min(decompression_info.blocks.len(), thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+    let next_block = AtomicUsize::new(0);
+    let recovered_images : Vec<AtomicBool> = decompression_info.images_info.iter().map(|_| AtomicBool::new(false)).collect();
+    let damaged_blocks : Mutex<Vec<(usize, String)>> = Mutex::new(Vec::new());
+    let decode_result = thread::scope(|scope| {
+        let decoders : Vec<_> = (0..num_decoders).map(|_| scope.spawn(|| -> Result<(), String> {
+            loop {
+                let block_i = next_block.fetch_add(1, Ordering::SeqCst);
+                if block_i >= decompression_info.blocks.len() {
+                    return Ok(());
+                }
+
+                let block_result = archive.read_solid_block(block_i).and_then(|block_data| {
+                    archive.decode_solid_block(block_i, &block_data, settings.debug_mode, |img_i, full_image| {
+                        let metadata = &decompression_info.images_info[img_i];
+                        println!("{}/{}: diff: ({:4},{:4}) full: ({:4},{:4}) {}",
+                                 img_i + 1, decompression_info.images_info.len(),
+                                 metadata.diff_width, metadata.diff_height,
+                                 metadata.output_width, metadata.output_height,
+                                 metadata.output_path);
+
+                        //the next image is diffed against this one, so the workers get their own copy
+                        save_pool.save(full_image.clone(), output_paths[img_i].clone());
+                        recovered_images[img_i].store(true, Ordering::SeqCst);
+                    })
+                });
+
+                //in salvage mode, the images before the damage have already been saved - skip to the next block
+                match block_result {
+                    Ok(()) => {},
+                    Err(e) if settings.salvage => {
+                        println!("WARNING: solid block {} is damaged, skipping the rest of it: {}", block_i, e);
+                        damaged_blocks.lock().unwrap().push((block_i, e));
+                    },
+                    Err(e) => return Err(format!("{} (use --salvage to recover the undamaged images)", e)),
                 }
             }
+        })).collect();
+
+        //report the first error from any decoder, once they have all finished
+        let decoder_results : Vec<Result<(), String>> = decoders.into_iter()
+            .map(|decoder| decoder.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+            .collect();
+        decoder_results.into_iter().collect::<Result<(), String>>()
+    });
+
+    //wait for the remaining images to be written
+    let save_result = save_pool.finish();
+    decode_result?;
+    let (mut num_written, mut num_skipped) = save_result?;
+
+    //duplicates are created last, so the image they copy is guaranteed to have been written
+    for (img_i, metadata) in decompression_info.images_info.iter().enumerate() {
+        if let Some(original_index) = metadata.duplicate_of {
+            let original_path = &output_paths[original_index];
+            let output_image_path = &output_paths[img_i];
+            if !recovered_images[original_index].load(Ordering::SeqCst) {
+                println!("{}/{}: {} is a duplicate of {}, which could not be recovered", img_i + 1, decompression_info.images_info.len(), metadata.output_path, original_path.display());
+                continue;
+            }
+            recovered_images[img_i].store(true, Ordering::SeqCst);
+            println!("{}/{}: {} is a duplicate of {}", img_i + 1, decompression_info.images_info.len(), metadata.output_path, original_path.display());
+            create_parent_folder(output_image_path)?;
+            if should_write(output_image_path, settings.overwrite_policy, || fs::read(original_path).ok() == fs::read(output_image_path).ok()) {
+                write_duplicate(original_path, output_image_path, settings.hardlink_duplicates)?;
+                num_written += 1;
+            }
+            else {
+                num_skipped += 1;
+            }
+        }
+    }
+
+    if num_skipped > 0 {
+        println!("Wrote {} image(s), skipped {} which already existed", num_written, num_skipped);
+    }
+
+    let damaged_blocks = damaged_blocks.into_inner().unwrap();
+    if damaged_blocks.is_empty() {
+        return Ok(());
+    }
+    print_salvage_report(decompression_info, &damaged_blocks, &recovered_images);
+
+    let num_recovered = recovered_images.iter().filter(|recovered| recovered.load(Ordering::SeqCst)).count();
+    Err(format!("Archive is damaged - only {} of {} images were recovered", num_recovered, recovered_images.len()))
+}
+
+/// Prints where each damaged solid block broke, and which of its images were lost
+fn print_salvage_report(decompression_info : &DecompressionInfo, damaged_blocks : &[(usize, String)], recovered_images : &[AtomicBool])
+{
+    println!("\n\n ---------- Salvage Report ----------");
+    let mut damaged_blocks = damaged_blocks.to_vec();
+    damaged_blocks.sort();
+    for (block_i, error) in damaged_blocks {
+        let block = &decompression_info.blocks[block_i];
+        let block_images = block.first_image..block.first_image + block.num_images;
+        let num_recovered = block_images.clone().filter(|&img_i| recovered_images[img_i].load(Ordering::SeqCst)).count();
+
+        println!("Solid block {} (images {} to {}): recovered {} of {} images", block_i, block_images.start, block_images.end - 1, num_recovered, block.num_images);
+        println!("    stream broke at: {}", error);
+        for img_i in block_images.filter(|&img_i| !recovered_images[img_i].load(Ordering::SeqCst)) {
+            println!("    lost: {}", decompression_info.images_info[img_i].output_path);
         }
+    }
+}
+
+/// Applies the overwrite policy to one output file. matches_existing is only called (in resume mode) if the file
+/// exists, and should check whether the existing file already holds the right image.
+fn should_write<F : FnOnce() -> bool>(output_image_path : &Path, overwrite_policy : OverwritePolicy, matches_existing : F) -> bool
+{
+    if !output_image_path.exists() {
+        return true;
+    }
+
+    match overwrite_policy {
+        OverwritePolicy::Overwrite | OverwritePolicy::FailIfExists => true,
+        OverwritePolicy::SkipExisting => false,
+        OverwritePolicy::Resume => !matches_existing(),
+    }
+}
+
+/// Checks whether the png file at path decodes to exactly the given image
+fn file_matches_image(path : &Path, expected_image : &RgbaImage) -> bool
+{
+    match image::open(path) {
+        Ok(existing_image) => hash_image(&existing_image.to_rgba8()) == hash_image(expected_image),
+        Err(_) => false,
+    }
+}
+
+/// Creates the folder(s) an output image is written to
+fn create_parent_folder(output_image_path : &Path) -> Result<(), String>
+{
+    match output_image_path.parent() {
+        Some(parent) => fs::create_dir_all(parent).map_err(|e| format!("Unable to create folder {}: {}", parent.display(), e)),
+        None => Ok(()),
+    }
+}
 
-        //copy pixels which were different in the new image
-        let mut pixel_count = 0;
-        for (x,y) in BlockXYIterator::new(50, (metadata.diff_width as usize, metadata.diff_height as usize)) {
+fn write_duplicate(original_path : &Path, output_image_path : &Path, hardlink : bool) -> Result<(), String>
+{
+    if hardlink {
+        //hard_link fails if the output already exists
+        if output_image_path.exists() {
+            fs::remove_file(output_image_path).map_err(|e| format!("Unable to replace {}: {}", output_image_path.display(), e))?;
+        }
+        fs::hard_link(original_path, output_image_path)
+            .map_err(|e| format!("Unable to hardlink {} to {}: {}", output_image_path.display(), original_path.display(), e))
+    }
+    else {
+        fs::copy(original_path, output_image_path)
+            .map(|_| ())
+            .map_err(|e| format!("Unable to copy {} to {}: {}", original_path.display(), output_image_path.display(), e))
+    }
+}
 
-            let full_image_x = x + metadata.x;
-            let full_image_y = y + metadata.y;
+/// A single image waiting to be encoded and written to disk by a save worker
+struct SaveJob {
+    image : RgbaImage,
+    output_path : PathBuf,
+}
 
-            //pixels are different - decompress a pixel from the compressed image data
-            if cropped_bitmap[pixel_count] == 1 {
-                let mut pixel_raw_data = [0u8; 4];
-                image_data_decompressor.read_exact(&mut pixel_raw_data).unwrap();
-                *full_image.get_pixel_mut(full_image_x, full_image_y) = image::Rgba::<u8>(pixel_raw_data);
+/// Pool of threads which png encode (and optionally oxipng optimize) the extracted images.
+/// Jobs are passed through a bounded channel, so if the workers fall behind the extraction
+/// thread blocks instead of holding every decoded image in memory.
+struct SaveWorkerPool {
+    job_sender : SyncSender<SaveJob>,
+    workers : Vec<thread::JoinHandle<()>>,
+    counts : Arc<SaveCounts>,
+}
+
+/// How many images the save workers wrote, how many they skipped because of the overwrite policy,
+/// and the errors for any images which couldn't be written
+#[derive(Default)]
+struct SaveCounts {
+    written : AtomicUsize,
+    skipped : AtomicUsize,
+    errors : Mutex<Vec<String>>,
+}
+
+impl SaveWorkerPool {
+    fn new(oxipng_options : Option<oxipng::Options>, overwrite_policy : OverwritePolicy) -> SaveWorkerPool
+    {
+        let num_workers = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let (job_sender, job_receiver) = sync_channel::<SaveJob>(num_workers * 2);
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let oxipng_options = Arc::new(oxipng_options);
+        let counts = Arc::new(SaveCounts::default());
+
+        let workers = (0..num_workers).map(|_| {
+            let job_receiver = Arc::clone(&job_receiver);
+            let oxipng_options = Arc::clone(&oxipng_options);
+            let counts = Arc::clone(&counts);
+            thread::spawn(move || save_worker(&job_receiver, &oxipng_options, overwrite_policy, &counts))
+        }).collect();
+
+        SaveWorkerPool {
+            job_sender,
+            workers,
+            counts,
+        }
+    }
+
+    fn save(&self, image : RgbaImage, output_path : PathBuf)
+    {
+        self.job_sender.send(SaveJob { image, output_path }).expect("All save workers have stopped");
+    }
+
+    /// Closes the job queue and blocks until every queued image has been written.
+    /// Returns the number of images written, and the number skipped because of the overwrite policy,
+    /// or an error if any image couldn't be written.
+    fn finish(self) -> Result<(usize, usize), String>
+    {
+        drop(self.job_sender);
+        for worker in self.workers {
+            if let Err(panic) = worker.join() {
+                std::panic::resume_unwind(panic);
             }
+        }
 
-            pixel_count += 1;
+        let errors = self.counts.errors.lock().unwrap();
+        if let Some(first_error) = errors.first() {
+            return Err(format!("Failed to write {} image(s): {}", errors.len(), first_error));
         }
+        Ok((self.counts.written.load(Ordering::SeqCst), self.counts.skipped.load(Ordering::SeqCst)))
+    }
+}
 
-        //create the folder(s) to put the image in, then save the image
-        let output_image_path = Path::new("output_images").join(&metadata.output_path);
-        fs::create_dir_all(output_image_path.parent().unwrap()).unwrap();
-
-        match &oxipng_options {
-            None => full_image.save(output_image_path).unwrap(),
-            Some(oxipng_options) => {
-                //TODO: oxipng doesn't seem to accept raw images - only png images.
-                //      in the future see if accept raw images, to avoid double compression/decompression
-                let mut unoptimized_png_in_memory = Vec::new(); //TODO:at least size of current image - prevent reallocation
-                {
-                    let ref mut w = std::io::BufWriter::new(&mut unoptimized_png_in_memory);
-                    let mut encoder = png::Encoder::new(w, full_image.width(), full_image.height()); // Width is 2 pixels and height is 1.
-                    encoder.set_color(png::ColorType::Rgba);
-                    encoder.set_depth(png::BitDepth::Eight);
-                    let mut writer = encoder.write_header().unwrap();
-
-                    writer.write_image_data(&full_image.clone().into_raw()).unwrap(); // Save
-                }
+fn save_worker(job_receiver : &Mutex<Receiver<SaveJob>>, oxipng_options : &Option<oxipng::Options>, overwrite_policy : OverwritePolicy, counts : &SaveCounts)
+{
+    loop {
+        //only hold the lock while waiting for a job, not while encoding it
+        let job = match job_receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return, //the pool was finished and the queue is empty
+        };
 
-                let optimized_png = oxipng::optimize_from_memory(&unoptimized_png_in_memory[..], &oxipng_options).unwrap();
-                std::fs::write(&output_image_path, &optimized_png[..]).unwrap();
-            },
+        //checking an existing file is done here too, as decoding it is about as slow as encoding the image
+        //a failed image is reported when the pool is finished, the worker keeps going so the queue doesn't stall
+        if should_write(&job.output_path, overwrite_policy, || file_matches_image(&job.output_path, &job.image)) {
+            match create_parent_folder(&job.output_path).and_then(|_| save_image(&job.image, &job.output_path, oxipng_options)) {
+                Ok(()) => { counts.written.fetch_add(1, Ordering::SeqCst); },
+                Err(e) => counts.errors.lock().unwrap().push(e),
+            }
         }
+        else {
+            counts.skipped.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+fn save_image(full_image : &RgbaImage, output_image_path : &Path, oxipng_options : &Option<oxipng::Options>) -> Result<(), String>
+{
+    let write_error = |e : &dyn std::fmt::Display| format!("Unable to write {}: {}", output_image_path.display(), e);
+    match oxipng_options {
+        None => full_image.save(output_image_path).map_err(|e| write_error(&e)),
+        Some(oxipng_options) => {
+            //TODO: oxipng doesn't seem to accept raw images - only png images.
+            //      in the future see if accept raw images, to avoid double compression/decompression
+            let mut unoptimized_png_in_memory = Vec::with_capacity(full_image.as_raw().len());
+            {
+                let w = std::io::BufWriter::new(&mut unoptimized_png_in_memory);
+                let mut encoder = png::Encoder::new(w, full_image.width(), full_image.height());
+                encoder.set_color(png::ColorType::Rgba);
+                encoder.set_depth(png::BitDepth::Eight);
+                let mut writer = encoder.write_header().map_err(|e| write_error(&e))?;
+
+                writer.write_image_data(full_image.as_raw()).map_err(|e| write_error(&e))?; // Save
+            }
+
+            let optimized_png = oxipng::optimize_from_memory(&unoptimized_png_in_memory[..], oxipng_options).map_err(|e| write_error(&e))?;
+            std::fs::write(output_image_path, &optimized_png[..]).map_err(|e| write_error(&e))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-        prev_image = full_image;
+    use image::{Rgba, RgbaImage};
+
+    use codec::Codec;
+    use common::ScratchFolder;
+    use compress::tests::{compress_to_archive, fast_settings};
+    use super::{extract_archive_alt, file_matches_image, ExtractionSettings, OverwritePolicy};
+
+    //when the up to date file in the output folder was (supposedly) written, so it's easy to tell if it was rewritten
+    const OLD_FILE_TIME : Duration = Duration::from_secs(1_000_000);
+
+    struct PrepopulatedOutput {
+        scratch_folder : ScratchFolder,
+        images : Vec<RgbaImage>,
+    }
+
+    impl PrepopulatedOutput {
+        fn output_path(&self, image_i : usize) -> PathBuf
+        {
+            self.scratch_folder.path.join("output_images").join(format!("{:03}.png", image_i))
+        }
+
+        fn modified_time(&self, image_i : usize) -> SystemTime
+        {
+            fs::metadata(self.output_path(image_i)).unwrap().modified().unwrap()
+        }
+    }
+
+    /// Compresses three images, then fills the output folder with a stale copy of the first image and an up to date
+    /// copy of the second image (the third image is missing), and extracts the archive over them
+    fn extract_over_existing_files(overwrite_policy : OverwritePolicy) -> (PrepopulatedOutput, Result<(), String>)
+    {
+        let images : Vec<RgbaImage> = (0..3).map(|i| RgbaImage::from_fn(8, 6, |x, y| Rgba([x as u8 * 30, y as u8 * 40, i * 80, 255]))).collect();
+        let (archive, _) = compress_to_archive(&images, &fast_settings(Codec::Brotli));
+        let prepopulated = PrepopulatedOutput { scratch_folder : ScratchFolder::new().unwrap(), images };
+
+        let archive_path = prepopulated.scratch_folder.path.join("compressed_images.brotli");
+        fs::write(&archive_path, archive).unwrap();
+        fs::create_dir(prepopulated.scratch_folder.path.join("output_images")).unwrap();
+        RgbaImage::from_pixel(8, 6, Rgba([1, 2, 3, 255])).save(prepopulated.output_path(0)).unwrap();
+        prepopulated.images[1].save(prepopulated.output_path(1)).unwrap();
+        fs::File::options().write(true).open(prepopulated.output_path(1)).unwrap().set_modified(UNIX_EPOCH + OLD_FILE_TIME).unwrap();
+
+        let settings = ExtractionSettings { overwrite_policy, ..ExtractionSettings::default() };
+        let output_folder = prepopulated.scratch_folder.path.join("output_images");
+        let result = extract_archive_alt(archive_path.to_str().unwrap(), output_folder.to_str().unwrap(), &settings);
+        (prepopulated, result)
+    }
+
+    #[test]
+    fn overwrite_replaces_existing_files()
+    {
+        let (output, result) = extract_over_existing_files(OverwritePolicy::Overwrite);
+        result.unwrap();
+        for (image_i, image) in output.images.iter().enumerate() {
+            assert!(file_matches_image(&output.output_path(image_i), image));
+        }
+        assert_ne!(output.modified_time(1), UNIX_EPOCH + OLD_FILE_TIME);
+    }
+
+    #[test]
+    fn skip_existing_leaves_existing_files_alone()
+    {
+        let (output, result) = extract_over_existing_files(OverwritePolicy::SkipExisting);
+        result.unwrap();
+        assert!(!file_matches_image(&output.output_path(0), &output.images[0]));
+        assert_eq!(output.modified_time(1), UNIX_EPOCH + OLD_FILE_TIME);
+        assert!(file_matches_image(&output.output_path(2), &output.images[2]));
+    }
+
+    #[test]
+    fn fail_if_exists_writes_nothing()
+    {
+        let (output, result) = extract_over_existing_files(OverwritePolicy::FailIfExists);
+        assert!(result.unwrap_err().contains("already exists"));
+        assert!(!file_matches_image(&output.output_path(0), &output.images[0]));
+        assert_eq!(output.modified_time(1), UNIX_EPOCH + OLD_FILE_TIME);
+        assert!(!output.output_path(2).exists());
+    }
+
+    #[test]
+    fn resume_only_rewrites_files_which_differ()
+    {
+        let (output, result) = extract_over_existing_files(OverwritePolicy::Resume);
+        result.unwrap();
+        for (image_i, image) in output.images.iter().enumerate() {
+            assert!(file_matches_image(&output.output_path(image_i), image));
+        }
+        assert_eq!(output.modified_time(1), UNIX_EPOCH + OLD_FILE_TIME);
     }
 }
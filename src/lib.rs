@@ -0,0 +1,50 @@
+//! The spritezip library - the archive format, compressor and extractor used by the spritezip program.
+//! The reader (see the `reader` module) works on any `Read + Seek` source, so it can also be used on
+//! in-memory archives, for example by the fuzzing harnesses in the 'fuzz' folder.
+
+//rust file modules
+pub mod alphablend;
+pub mod analyze;
+pub mod bitmap_coder;
+pub mod byte_counter;
+pub mod codec;
+pub mod common;
+pub mod compress;
+pub mod debug_dump;
+pub mod extract;
+pub mod parity;
+pub mod reader;
+pub mod scan_order;
+pub mod stats;
+pub mod strategy;
+pub mod validate;
+pub mod verify;
+
+//crates
+#[macro_use]
+extern crate serde_derive;
+
+extern crate serde;
+extern crate serde_json;
+extern crate bincode;
+
+//external crates
+extern crate image;
+extern crate brotli;
+extern crate walkdir;
+extern crate number_prefix;
+extern crate oxipng;
+extern crate png;
+extern crate zstd;
+extern crate xz2;
+extern crate sha2;
+extern crate reed_solomon_erasure;
+extern crate crc32fast;
+
+//test-only crates
+#[cfg(test)]
+extern crate proptest;
+
+//standard crates
+extern crate core;
+extern crate time;
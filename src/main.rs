@@ -4,71 +4,219 @@
 ///      should make an iterator which only processes .png files!
 ///
 
-//rust file modules
-mod alphablend;
-mod common;
-mod compress;
-mod extract;
-
-//crates
-#[macro_use]
-extern crate serde_derive;
-
-extern crate serde;
-extern crate serde_json;
-extern crate bincode;
+//the compressor/extractor live in the library crate (src/lib.rs)
+extern crate spritezip;
 
 //external crates
-extern crate image;
-extern crate brotli;
-extern crate walkdir;
-extern crate number_prefix;
 extern crate oxipng;
-extern crate png;
-
-//standard crates
-extern crate core;
-extern crate time;
 
 //custom modules
-use alphablend::convert_folder_to_alphablend;
-use compress::alt_compression_2;
-use extract::extract_archive_alt;
-use common::verify_images;
-use common::VerificationResult;
+use spritezip::alphablend::convert_folder_to_alphablend;
+use spritezip::compress::alt_compression_2;
+use spritezip::debug_dump::debug_dump;
+use spritezip::analyze::analyze_images;
+use spritezip::stats::StatsFormat;
+use spritezip::compress::{CompressionSettings, SolidBlockLimits};
+use spritezip::codec::{Codec, CodecSettings};
+use spritezip::scan_order::{ScanOrder, DEFAULT_SCAN_ORDER};
+use spritezip::strategy::{strategy_by_name, DiffStrategy, BASELINE_STRATEGY};
+use spritezip::extract::{extract_archive_alt, ExtractionSettings, OverwritePolicy};
+use spritezip::parity::repair_archive;
+use spritezip::validate::ArchiveLimits;
+use spritezip::verify::{verify_images, ImageVerification, VerificationReport};
+use spritezip::common::{pretty_print_bytes, FileTypeIterator, ScratchFolder, get_png_dimensions};
 
 //standard uses
-use std::path::{Path};
+use std::path::Path;
 use std::env;
+use std::fs;
+use std::io::BufReader;
+use std::time::Instant;
 
-//TODO: take input/output folders as arguments
-fn do_compression(brotli_archive_path : &str)
+/// Compresses the input folder, optionally saving the per-image statistics as CSV or JSON
+fn do_compression(input_folder : &str, brotli_archive_path : &str, settings : &CompressionSettings, stats_path : Option<&str>)
 {
     println!("\n\n ---------- Begin Compression... ---------- ");
-    alt_compression_2(&brotli_archive_path);
+    //check the statistics can be saved before spending time on compression
+    let stats_file = stats_path.map(|stats_path| StatsFormat::from_path(stats_path).map(|format| (stats_path, format))).transpose();
+    let result = stats_file.and_then(|stats_file| {
+        let stats = alt_compression_2(input_folder, brotli_archive_path, settings)?;
+        if let Some((stats_path, format)) = stats_file {
+            stats.save(stats_path, format)?;
+            println!("Wrote per-image statistics to {}", stats_path);
+        }
+        Ok(())
+    });
+
+    if let Err(e) = result {
+        println!("ERROR: {}", e);
+        std::process::exit(-1);
+    }
 }
 
-//TODO: take input/output folders as arguments
-fn do_extraction(brotli_archive_path : &str, oxipng_options : Option<oxipng::Options>, debug_mode : bool)
+fn do_extraction(brotli_archive_path : &str, output_folder : &str, settings : &ExtractionSettings)
 {
     println!("\n\n ---------- Begin Extraction... ---------- ");
     if !Path::new(brotli_archive_path).exists() {
         println!("ERROR: Archive file [{}] does not exist! exiting...", brotli_archive_path);
         std::process::exit(-1);
     }
-    extract_archive_alt(&brotli_archive_path, oxipng_options, debug_mode);
+    if let Err(e) = extract_archive_alt(brotli_archive_path, output_folder, settings) {
+        println!("ERROR: {}", e);
+        std::process::exit(-1);
+    }
+}
+
+fn do_repair(brotli_archive_path : &str)
+{
+    println!("\n\n ---------- Begin Repair... ---------- ");
+    match repair_archive(brotli_archive_path) {
+        Ok(report) => {
+            if report.damaged_data_shards.is_empty() && report.damaged_parity_shards.is_empty() {
+                println!("No damage found in [{}]", brotli_archive_path);
+            }
+            else {
+                println!("Repaired {} damaged data shard(s) {:?} and {} damaged parity shard(s) {:?}",
+                         report.damaged_data_shards.len(), report.damaged_data_shards,
+                         report.damaged_parity_shards.len(), report.damaged_parity_shards);
+            }
+        },
+        Err(e) => {
+            println!("ERROR: {}", e);
+            std::process::exit(-1);
+        },
+    }
+}
+
+/// Prints each image which didn't match exactly, and a count of each kind of result
+fn print_verification_report(report : &VerificationReport)
+{
+    for file in report.files.iter().filter(|file| file.result != ImageVerification::ExactMatch) {
+        let details = match &file.result {
+            ImageVerification::ExactMatch => String::new(),
+            ImageVerification::InvisibleMatch { invisible_differences } =>
+                format!("{} invisible pixel(s) have different values (they may have been changed by oxipng)", invisible_differences),
+            ImageVerification::Mismatch { different_pixels, first_difference, expected_pixel, actual_pixel } =>
+                format!("{} pixel(s) differ, first at ({},{}): expected {:?}, got {:?}", different_pixels, first_difference.0, first_difference.1, expected_pixel, actual_pixel),
+            ImageVerification::DimensionMismatch { expected, actual } =>
+                format!("expected {}x{}, got {}x{}", expected.0, expected.1, actual.0, actual.1),
+            ImageVerification::Missing { error } | ImageVerification::InputUnreadable { error } => error.clone(),
+        };
+        println!("{}: {} - {}", file.path, file.result.name(), details);
+        if let Some(diff_image_error) = &file.diff_image_error {
+            println!("    diff image not saved: {}", diff_image_error);
+        }
+    }
+
+    let summary : Vec<String> = report.summary().iter().map(|(name, count)| format!("{} {}", count, name)).collect();
+    println!("Verified {} image(s): {}", report.files.len(), summary.join(", "));
+}
+
+/// Verifies every image, optionally writing the full report as JSON. Exits with an error code if any image failed.
+fn do_verify(input_folder : &str, output_folder : &str, json_report_path : Option<&str>, diff_folder : Option<&str>)
+{
+    println!("\n\n ---------- Begin Verification... ---------- ");
+    let report = verify_images(input_folder, output_folder, diff_folder);
+    print_verification_report(&report);
+
+    if let Some(json_report_path) = json_report_path {
+        if let Err(e) = fs::write(json_report_path, report.to_json()) {
+            println!("ERROR: Unable to write verification report to {}: {}", json_report_path, e);
+            std::process::exit(-1);
+        }
+        println!("Wrote verification report to {}", json_report_path);
+    }
+
+    if report.files.is_empty() {
+        println!("ERROR: no images found in '{}' to verify", input_folder);
+        std::process::exit(-1);
+    }
+    else if report.num_failures() > 0 {
+        println!("ERROR: {} of {} image(s) did not match!", report.num_failures(), report.files.len());
+        std::process::exit(-1);
+    }
+    else if report.all_exact() {
+        println!("All images match exactly!");
+    }
+    else {
+        println!("All images match, except for invisible pixels");
+    }
 }
 
-fn do_verify(input_folder: &str, output_folder: &str)
+/// Compresses the input folder, extracts the archive again and checks that every image matches exactly.
+/// The archive and extracted images go in a scratch folder, so the real archive and output folder are never touched.
+fn do_selftest(input_folder : &str, compression_settings : &CompressionSettings, extraction_settings : &ExtractionSettings) -> Result<(), String>
 {
+    let scratch_folder = ScratchFolder::new()?;
+    let archive_path = scratch_folder.path.join("compressed_images.brotli");
+    let archive_path = archive_path.to_str().ok_or("Scratch folder path is not valid unicode")?;
+    let output_folder = scratch_folder.path.join("output_images");
+    let output_folder = output_folder.to_str().ok_or("Scratch folder path is not valid unicode")?;
+    println!("Running self test in scratch folder [{}]", scratch_folder.path.display());
+
+    println!("\n\n ---------- Begin Compression... ---------- ");
+    let compression_start = Instant::now();
+    alt_compression_2(input_folder, archive_path, compression_settings)?;
+    let compression_time = compression_start.elapsed();
+
+    println!("\n\n ---------- Begin Extraction... ---------- ");
+    let extraction_start = Instant::now();
+    extract_archive_alt(archive_path, output_folder, extraction_settings)?;
+    let extraction_time = extraction_start.elapsed();
+
     println!("\n\n ---------- Begin Verification... ---------- ");
-    println!("Verification Result:");
+    let report = verify_images(input_folder, output_folder, None);
+    print_verification_report(&report);
+
+    //compare against both the .png files and the raw pixels they hold
+    let archive_bytes = fs::metadata(archive_path).map_err(|e| format!("Unable to read archive size: {}", e))?.len();
+    let (mut png_bytes, mut raw_bytes) = (0u64, 0u64);
+    for ent in FileTypeIterator::new(input_folder, "png") {
+        png_bytes += ent.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        if let Ok(file) = fs::File::open(ent.path()) {
+            if let Ok((width, height)) = get_png_dimensions(&mut BufReader::new(file)) {
+                raw_bytes += width as u64 * height as u64 * 4;
+            }
+        }
+    }
+
+    println!("\n\n ---------- Self Test Results ---------- ");
+    println!("Compression took {:.2}s, extraction took {:.2}s", compression_time.as_secs_f64(), extraction_time.as_secs_f64());
+    println!("Archive is {} - input .png files are {} ({:.2}x), raw pixels are {} ({:.2}x)",
+             pretty_print_bytes(archive_bytes as f64),
+             pretty_print_bytes(png_bytes as f64), png_bytes as f64 / archive_bytes as f64,
+             pretty_print_bytes(raw_bytes as f64), raw_bytes as f64 / archive_bytes as f64);
 
-    match verify_images(input_folder, output_folder) {
-        VerificationResult::ExactMatch => println!("All images match exactly!"),
-        VerificationResult::InvisibleMatch => println!("Warning - some pixels had invisible pixels with different values. They might have been optimized away by oxipng!"),
-        VerificationResult::Failure => println!("Error: at least one image did not match!"),
-        VerificationResult::NotFound => println!("Error: corresponding output image can't be opened or doesn't exist!"),
+    if report.all_exact() {
+        println!("PASSED: all images match exactly!");
+        Ok(())
+    }
+    else {
+        Err(format!("Self test FAILED: {} of {} image(s) did not match exactly", report.files.iter().filter(|file| file.result != ImageVerification::ExactMatch).count(), report.files.len()))
+    }
+}
+
+fn do_debug_dump(input_folder : &str, dump_folder : &str, settings : &CompressionSettings)
+{
+    println!("\n\n ---------- Begin Debug Dump... ---------- ");
+    match debug_dump(input_folder, dump_folder, settings) {
+        Ok(num_dumped) => println!("Dumped {} image(s) to {}", num_dumped, dump_folder),
+        Err(e) => {
+            println!("ERROR: {}", e);
+            std::process::exit(-1);
+        },
+    }
+}
+
+fn do_analyze(input_folder : &str, settings : &CompressionSettings)
+{
+    println!("\n\n ---------- Begin Analysis... ---------- ");
+    match analyze_images(input_folder, settings) {
+        Ok(report) => report.print(),
+        Err(e) => {
+            println!("ERROR: {}", e);
+            std::process::exit(-1);
+        },
     }
 }
 
@@ -82,10 +230,202 @@ fn do_alphablend(reverse : bool)
     }
 }
 
+/// Returns the value given after a '--name value' style argument, or None if the argument wasn't given
+fn get_argument_value<'a>(args : &'a [String], name : &str) -> Option<&'a str>
+{
+    let position = args.iter().position(|arg| arg == name)?;
+    match args.get(position + 1) {
+        Some(value) => Some(value),
+        None => {
+            println!("ERROR: argument '{}' requires a value", name);
+            print_description_and_exit();
+        }
+    }
+}
+
+/// Parses the value of a '--name value' style argument, exiting the program if it is invalid
+fn parse_argument_value<T>(args : &[String], name : &str) -> Option<T>
+where T : std::str::FromStr,
+      T::Err : std::fmt::Display
+{
+    get_argument_value(args, name).map(|value| match value.parse::<T>() {
+        Ok(parsed_value) => parsed_value,
+        Err(e) => {
+            println!("ERROR: Invalid value {} for '{}' argument (reason: {}) - exiting", value, name, e);
+            print_description_and_exit();
+        }
+    })
+}
+
+/// Like parse_argument_value, but also exits if is_valid rejects the value. requirement describes the valid values.
+fn parse_checked_argument_value<T, F>(args : &[String], name : &str, is_valid : F, requirement : &str) -> Option<T>
+where T : std::str::FromStr + std::fmt::Display,
+      T::Err : std::fmt::Display,
+      F : Fn(&T) -> bool
+{
+    parse_argument_value::<T>(args, name).map(|value| {
+        if !is_valid(&value) {
+            println!("ERROR: Invalid value {} for '{}' argument (reason: {}) - exiting", value, name, requirement);
+            print_description_and_exit();
+        }
+        value
+    })
+}
+
+/// Gets the codec settings for one stream - the per-stream codec argument takes priority over '--codec'
+fn get_codec_settings(args : &[String], stream_codec_argument : &str) -> CodecSettings
+{
+    let codec_name = get_argument_value(args, stream_codec_argument).or_else(|| get_argument_value(args, "--codec"));
+    let codec = match codec_name.map(Codec::from_name) {
+        None => Codec::Brotli,
+        Some(Ok(codec)) => codec,
+        Some(Err(e)) => {
+            println!("ERROR: {}", e);
+            print_description_and_exit();
+        },
+    };
+
+    let mut settings = codec.default_settings();
+    if let Some(quality) = parse_argument_value::<u32>(args, "--quality") {
+        settings.quality = quality;
+    }
+    if let Some(window) = parse_argument_value::<u32>(args, "--window") {
+        settings.window = window;
+    }
+    if let Some(buffer_size) = parse_argument_value::<usize>(args, "--buffer-size") {
+        settings.buffer_size = buffer_size;
+    }
+    if let Err(e) = settings.validate() {
+        println!("ERROR: {}", e);
+        print_description_and_exit();
+    }
+    settings
+}
+
+/// Gets the scan order from '--scan-order', or the default if it isn't given
+fn get_scan_order(args : &[String]) -> ScanOrder
+{
+    match get_argument_value(args, "--scan-order").map(ScanOrder::from_name) {
+        None => DEFAULT_SCAN_ORDER,
+        Some(Ok(scan_order)) => scan_order,
+        Some(Err(e)) => {
+            println!("ERROR: {}", e);
+            print_description_and_exit();
+        },
+    }
+}
+
+/// Gets the encoding strategy id from '--strategy NAME' (baseline if it isn't given). It can't be combined with '--auto'.
+fn get_strategy(args : &[String]) -> u8
+{
+    let name = match get_argument_value(args, "--strategy") {
+        None => return BASELINE_STRATEGY.id(),
+        Some(name) => name,
+    };
+    if args.iter().any(|arg| arg == "--auto") {
+        println!("ERROR: '--strategy' and '--auto' can't be used together");
+        print_description_and_exit();
+    }
+    match strategy_by_name(name) {
+        Ok(strategy) if strategy.is_bidirectional() => {
+            println!("ERROR: the {} strategy can't be used for every image - use '--bidirectional' instead", name);
+            print_description_and_exit();
+        },
+        Ok(strategy) => strategy.id(),
+        Err(e) => {
+            println!("ERROR: {}", e);
+            print_description_and_exit();
+        },
+    }
+}
+
+/// Gets the overwrite policy from the '--overwrite', '--skip-existing', '--fail-if-exists' and '--resume' flags (at most one may be given)
+fn get_overwrite_policy(args : &[String]) -> OverwritePolicy
+{
+    let policies = [
+        ("--overwrite", OverwritePolicy::Overwrite),
+        ("--skip-existing", OverwritePolicy::SkipExisting),
+        ("--fail-if-exists", OverwritePolicy::FailIfExists),
+        ("--resume", OverwritePolicy::Resume),
+    ];
+
+    let given_policies : Vec<&(&str, OverwritePolicy)> = policies.iter().filter(|(flag, _)| args.iter().any(|arg| arg == flag)).collect();
+    match given_policies.len() {
+        0 => OverwritePolicy::default(),
+        1 => given_policies[0].1,
+        _ => {
+            println!("ERROR: only one of '--overwrite', '--skip-existing', '--fail-if-exists' and '--resume' can be given");
+            print_description_and_exit();
+        },
+    }
+}
+
+/// Gets the extraction limits, overriding the defaults with any limits given on the command line
+fn get_archive_limits(args : &[String]) -> ArchiveLimits
+{
+    let mut limits = ArchiveLimits::default();
+    if let Some(max_image_dimension) = parse_argument_value::<u32>(args, "--max-dimension") {
+        limits.max_image_dimension = max_image_dimension;
+    }
+    if let Some(max_image_pixels) = parse_argument_value::<u64>(args, "--max-image-pixels") {
+        limits.max_image_pixels = max_image_pixels;
+    }
+    if let Some(max_total_pixels) = parse_argument_value::<u64>(args, "--max-total-pixels") {
+        limits.max_total_pixels = max_total_pixels;
+    }
+    if let Some(max_metadata_mb) = parse_argument_value::<f64>(args, "--max-metadata-mb") {
+        limits.max_metadata_bytes = (max_metadata_mb * 1_000_000.0) as u64;
+    }
+    if let Some(max_window_log) = parse_argument_value::<u32>(args, "--max-window") {
+        limits.max_window_log = max_window_log;
+    }
+    limits
+}
+
 fn print_description_and_exit() -> !
 {
     println!("\n------------------------------- Usage Instructions -------------------------------------");
-    println!("spritezip [compress|extract [0|1|2|3|4|5|6]|verify|selftest|alphablend|undo_alphablend]");
+    println!("spritezip [compress|extract [0|1|2|3|4|5|6]|repair|verify|selftest|analyze|debug-dump|alphablend|undo_alphablend]");
+    println!("When compressing, '--block-images N' and/or '--block-mb X' split the archive into independently");
+    println!("compressed solid blocks of at most N images / X megabytes of uncompressed image data.");
+    println!("Smaller blocks decode in parallel and limit the damage from corruption, but compress worse.");
+    println!("'--codec [brotli|zstd|xz]' picks the compressor for all streams, or use '--image-codec', '--bitmap-codec'");
+    println!("and '--metadata-codec' to pick per stream. '--quality N', '--window N' (log2 of window size) and");
+    println!("'--buffer-size N' override the codec defaults (brotli: quality 11, window 24).");
+    println!("'--parity N' appends Reed-Solomon parity data of about N% of the archive size (1 to 100). If the archive");
+    println!("is later damaged, 'spritezip repair' rebuilds it, as long as no more than about N% of it was damaged.");
+    println!("'--scan-order [blocks[:N]|rows|snake|snake-blocks[:N]|hilbert|z-order]' sets the order the pixels of each image");
+    println!("are stored in (default blocks:50, square blocks of 50 pixels). It is stored in the archive for extraction.");
+    println!("'--context-bitmap' codes the difference bitmaps with a dedicated context-modeling coder instead of '--bitmap-codec'.");
+    println!("'--auto' tries every encoding strategy (scan order, predictor, keyframe) on each image with a fast codec,");
+    println!("and keeps the one which compresses smallest. Slower, but often gives a smaller archive.");
+    println!("'--strategy NAME' encodes every image with one strategy instead (default baseline). For example '--strategy motion'");
+    println!("searches for parts of the sprite which moved between images, which helps when e.g. only an arm or a weapon moves.");
+    println!("'--bidirectional' stores images as B-frames, predicted from both the image before and the image after them,");
+    println!("where that is estimated to be smaller (e.g. the middle frame of a blink). Can be combined with the above.");
+    println!("'--stats FILE' saves the dimensions, diff, crop region and bytes added to the archive of each image to FILE,");
+    println!("as CSV or JSON depending on its extension (.csv or .json).");
+    println!("Images which are exact duplicates of an earlier image are only stored once. When extracting, they are");
+    println!("copied from the first image, or hardlinked to it if '--hardlink' is given.");
+    println!("Extraction refuses archives whose paths would write outside 'output_images' (absolute paths, '..' etc.)");
+    println!("unless '--allow-unsafe-paths' is given. Only use it for archives you trust!");
+    println!("Archives with images/metadata over the extraction limits are refused. The limits can be changed with");
+    println!("'--max-dimension N', '--max-image-pixels N', '--max-total-pixels N', '--max-metadata-mb X' and '--max-window N'");
+    println!("(log2 of the largest zstd/xz window, needed if the archive was compressed with '--window' over 27).");
+    println!("If an archive is damaged, '--salvage' extracts every image which can still be rebuilt, skipping ahead to the");
+    println!("next solid block after the damage, and reports which images were lost.");
+    println!("Existing files in 'output_images' are overwritten ('--overwrite', the default). '--skip-existing' leaves them alone,");
+    println!("'--fail-if-exists' refuses to extract if any exist, and '--resume' only rewrites files which don't already hold the");
+    println!("right image, to continue an interrupted extraction.");
+    println!("'verify' compares every image in 'input_images' with 'output_images', and exits with an error if any don't match.");
+    println!("'--json FILE' also writes the per-image results to FILE as JSON, and '--diff-images' saves a picture of the");
+    println!("differences in each mismatched image to 'verify_diffs'.");
+    println!("'selftest' compresses 'input_images', extracts and verifies it in a temporary folder (the real archive and");
+    println!("'output_images' aren't touched), reports the time taken and compression ratio, and exits with an error on any mismatch.");
+    println!("'analyze' measures how different neighbouring images are (also for other image orders and alignments),");
+    println!("estimates the compressed size with fast codec settings and recommends settings, without writing anything.");
+    println!("'debug-dump' runs the compressor without writing an archive, and saves the reference, aligned overlay, diff");
+    println!("rectangle and difference bitmap of each image, plus a heatmap of changes for each folder, to 'debug_dump'.");
     println!("If you use 'spritezip extract' by itself, .png files are not optimized");
     println!("Specifying a number (2 is recommended) will cause oxipng to optimize the .png files before saving them.");
     println!("For example 'spritezip extract 2' will use level 2 compression (where 0 is fast and largest size, 6 is extremely slow and smallest size)");
@@ -104,9 +444,6 @@ fn main()
     let input_path = Path::new(input_folder);
     std::fs::create_dir_all(input_path).unwrap();
 
-    //check if the output folder already exists
-    let output_folder_exists = Path::new(output_folder).exists();
-
     //TODO: use 'clap' to parse arugments
     //Use command line arguments to set program mode
     let args: Vec<String> = env::args().collect();
@@ -129,7 +466,7 @@ fn main()
     let oxipng_options = if mode != Some("extract") {
             None
         }
-        else if args.len() < 3 {
+        else if args.len() < 3 || args[2].starts_with("--") {
             println!("INFO: 'optimize' argument NOT given - PNG files will not be optimized for size when extracting!");
             None
         } else {
@@ -155,30 +492,61 @@ fn main()
         };
 
 
+    let extraction_settings = ExtractionSettings {
+        oxipng_options,
+        debug_mode,
+        hardlink_duplicates : args.iter().any(|arg| arg == "--hardlink"),
+        allow_unsafe_paths : args.iter().any(|arg| arg == "--allow-unsafe-paths"),
+        limits : get_archive_limits(&args),
+        salvage : args.iter().any(|arg| arg == "--salvage"),
+        overwrite_policy : get_overwrite_policy(&args),
+    };
+
+    let compression_settings = CompressionSettings {
+        block_limits : SolidBlockLimits {
+            max_images : parse_checked_argument_value::<usize, _>(&args, "--block-images", |&images| images > 0, "must be at least 1"),
+            max_uncompressed_bytes : parse_checked_argument_value::<f64, _>(&args, "--block-mb", |mb| mb.is_finite() && (mb * 1_000_000.0) as u64 > 0, "must be a positive number of MB")
+                .map(|mb| (mb * 1_000_000.0) as u64),
+        },
+        image_codec : get_codec_settings(&args, "--image-codec"),
+        bitmap_codec : get_codec_settings(&args, "--bitmap-codec"),
+        metadata_codec : get_codec_settings(&args, "--metadata-codec"),
+        parity_percent : parse_checked_argument_value::<u32, _>(&args, "--parity", |percent| (1..=100).contains(percent), "must be from 1 to 100"),
+        auto_strategy : args.iter().any(|arg| arg == "--auto"),
+        strategy : get_strategy(&args),
+        scan_order : get_scan_order(&args),
+        context_coded_bitmap : args.iter().any(|arg| arg == "--context-bitmap"),
+        bidirectional : args.iter().any(|arg| arg == "--bidirectional"),
+    };
+
     match mode {
         Some("compress") => {
-            //TODO: compression produces an output file, even if input images directory is empty
-            do_compression(brotli_archive_path);
+            do_compression(input_folder, brotli_archive_path, &compression_settings, get_argument_value(&args, "--stats"));
         },
         Some("extract") | None => {
             if mode == None {
                 println!("No arguments supplied - will try to extract the default archive [{}]...", brotli_archive_path);
             }
-            do_extraction(brotli_archive_path, oxipng_options, debug_mode);
+            do_extraction(brotli_archive_path, output_folder, &extraction_settings);
+        },
+        Some("repair") => {
+            do_repair(brotli_archive_path);
         },
         Some("verify") => {
-            do_verify(input_folder, output_folder);
+            let diff_folder = if args.iter().any(|arg| arg == "--diff-images") { Some("verify_diffs") } else { None };
+            do_verify(input_folder, output_folder, get_argument_value(&args, "--json"), diff_folder);
         }
         Some("selftest") => {
-            if output_folder_exists {
-                println!("ERROR: Can't run Self Test because output folder already exists!");
-                println!("Please delete the folder [{}] as it may already contain 'correct' files, giving a false test result", output_folder);
-                print_description_and_exit();
+            if let Err(e) = do_selftest(input_folder, &compression_settings, &extraction_settings) {
+                println!("ERROR: {}", e);
+                std::process::exit(-1);
             }
-
-            do_compression(brotli_archive_path);
-            do_extraction(brotli_archive_path, oxipng_options, debug_mode);
-            do_verify(input_folder, output_folder);
+        },
+        Some("analyze") => {
+            do_analyze(input_folder, &compression_settings);
+        },
+        Some("debug-dump") => {
+            do_debug_dump(input_folder, "debug_dump", &compression_settings);
         },
         Some("alphablend") => {
             do_alphablend(false);
@@ -0,0 +1,347 @@
+//standard uses
+use std;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+//non-standard use
+use bincode;
+use crc32fast;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use common::{pretty_print_bytes, TemporaryArchivePath};
+use common::{u64_to_u8_buf_little_endian, u8_buf_to_u64_little_endian};
+
+// The parity section protects everything before it (header, solid blocks and metadata) with Reed-Solomon
+// parity. The protected bytes are split into PARITY_DATA_SHARDS equal sized shards (the last one zero padded),
+// and any damaged shards can be rebuilt as long as no more shards are damaged than there are parity shards.
+// A CRC32 of every shard is stored so damaged shards can be found.
+//
+// format                              data name                       description
+//---------------------------------------------------------------------------------------------------
+//[ParityInfo + ParityTrailer]          first copy                      - used if the end of the file is damaged
+//[parity_shards * shard_size bytes]    parity shards
+//[ParityInfo + ParityTrailer]          second copy                     - the trailer is the last 32 bytes of the file
+//
+// The ParityInfo is stored uncompressed with bincode, so it can be read without any of the codecs.
+
+pub const PARITY_DATA_SHARDS: usize = 128;
+pub const PARITY_TRAILER_LENGTH: usize = 32;
+pub const PARITY_MAGIC: [u8; 4] = *b"SPZP";
+pub const PARITY_VERSION: u8 = 1;
+//the ParityInfo of even a huge archive is a few kB, anything larger is damaged
+const MAX_PARITY_INFO_LENGTH: u64 = 1024 * 1024;
+
+/// Describes the shards of the parity section
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ParityInfo {
+    //number of bytes protected, from the start of the file (this is also where the parity section starts)
+    pub data_length : u64,
+    pub shard_size : u64,
+    pub data_shards : usize,
+    pub parity_shards : usize,
+    //where the parity shards start in the file
+    pub parity_shards_start : u64,
+    //crc32 of each data shard, then each parity shard
+    pub shard_checksums : Vec<u32>,
+}
+
+/// The fixed size trailer which follows each copy of the ParityInfo
+/// format                              data name
+///---------------------------------------------------------------------------------------------------
+///[4 bytes]                            magic ('SPZP')
+///[u8]                                 parity format version
+///[3 bytes]                            reserved (zero)
+///[u64, little endian]                 length of the ParityInfo before this trailer
+///[u64, little endian]                 crc32 of the ParityInfo
+///[8 bytes]                            reserved (zero)
+struct ParityTrailer {
+    info_length : u64,
+    info_checksum : u32,
+}
+
+impl ParityTrailer {
+    fn to_bytes(&self) -> [u8; PARITY_TRAILER_LENGTH]
+    {
+        let mut bytes = [0u8; PARITY_TRAILER_LENGTH];
+        bytes[0..4].copy_from_slice(&PARITY_MAGIC);
+        bytes[4] = PARITY_VERSION;
+        bytes[8..16].copy_from_slice(&u64_to_u8_buf_little_endian(self.info_length));
+        bytes[16..24].copy_from_slice(&u64_to_u8_buf_little_endian(self.info_checksum as u64));
+        bytes
+    }
+
+    fn from_bytes(bytes : &[u8]) -> Option<ParityTrailer>
+    {
+        if bytes.len() < PARITY_TRAILER_LENGTH || bytes[0..4] != PARITY_MAGIC || bytes[4] != PARITY_VERSION {
+            return None;
+        }
+
+        let mut info_length_bytes = [0u8; 8];
+        info_length_bytes.copy_from_slice(&bytes[8..16]);
+        let mut info_checksum_bytes = [0u8; 8];
+        info_checksum_bytes.copy_from_slice(&bytes[16..24]);
+
+        Some(ParityTrailer {
+            info_length : u8_buf_to_u64_little_endian(&info_length_bytes),
+            info_checksum : u8_buf_to_u64_little_endian(&info_checksum_bytes) as u32,
+        })
+    }
+}
+
+/// Splits the protected bytes into shards of shard_size, zero padding the last one.
+/// Shards past the end of a truncated file are None.
+fn split_into_shards(data : &[u8], shard_size : usize, num_shards : usize) -> Vec<Option<Vec<u8>>>
+{
+    (0..num_shards).map(|shard_i| {
+        let shard_start = shard_i * shard_size;
+        if shard_start >= data.len() {
+            return None;
+        }
+        let mut shard = data[shard_start..std::cmp::min(shard_start + shard_size, data.len())].to_vec();
+        shard.resize(shard_size, 0);
+        Some(shard)
+    }).collect()
+}
+
+/// Works out how many parity shards give (at least) the requested overhead, as a percentage of the protected data
+pub fn parity_shards_for_percent(parity_percent : u32) -> usize
+{
+    let parity_shards = (PARITY_DATA_SHARDS * parity_percent as usize).div_ceil(100);
+    parity_shards.clamp(1, 256 - PARITY_DATA_SHARDS)
+}
+
+/// Serializes a ParityInfo followed by its trailer
+fn parity_info_with_trailer(parity_info : &ParityInfo) -> Vec<u8>
+{
+    let mut bytes = bincode::serialize(parity_info).unwrap();
+    let trailer = ParityTrailer {
+        info_length : bytes.len() as u64,
+        info_checksum : crc32fast::hash(&bytes),
+    };
+    bytes.extend_from_slice(&trailer.to_bytes());
+    bytes
+}
+
+/// Appends a parity section protecting the first data_length bytes of the archive, which must already be
+/// complete (including the header). The archive must be open for reading and writing.
+pub fn append_parity<F : Read + Write + Seek>(archive_file : &mut F, data_length : u64, parity_shards : usize) -> Result<ParityInfo, String>
+{
+    let mut data = vec![0u8; data_length as usize];
+    archive_file.seek(SeekFrom::Start(0)).map_err(|e| format!("Unable to seek to start of archive: {}", e))?;
+    archive_file.read_exact(&mut data).map_err(|e| format!("Unable to read archive to compute parity: {}", e))?;
+
+    let shard_size = (data.len()).div_ceil(PARITY_DATA_SHARDS);
+    let data_shards : Vec<Vec<u8>> = split_into_shards(&data, shard_size, PARITY_DATA_SHARDS).into_iter()
+        .map(|shard| shard.unwrap_or_else(|| vec![0u8; shard_size]))
+        .collect();
+    let mut parity = vec![vec![0u8; shard_size]; parity_shards];
+
+    let reed_solomon = ReedSolomon::new(PARITY_DATA_SHARDS, parity_shards).map_err(|e| format!("Invalid parity settings: {:?}", e))?;
+    reed_solomon.encode_sep(&data_shards, &mut parity).map_err(|e| format!("Unable to compute parity: {:?}", e))?;
+
+    //the first copy of the info is written before the parity shards, so its length has to be known up front
+    let mut parity_info = ParityInfo {
+        data_length,
+        shard_size : shard_size as u64,
+        data_shards : PARITY_DATA_SHARDS,
+        parity_shards,
+        parity_shards_start : 0,
+        shard_checksums : data_shards.iter().chain(parity.iter()).map(|shard| crc32fast::hash(shard)).collect(),
+    };
+    parity_info.parity_shards_start = data_length + parity_info_with_trailer(&parity_info).len() as u64;
+    let info_bytes = parity_info_with_trailer(&parity_info);
+
+    archive_file.seek(SeekFrom::Start(data_length)).map_err(|e| format!("Unable to seek to parity section: {}", e))?;
+    archive_file.write_all(&info_bytes).map_err(|e| format!("Unable to write parity info: {}", e))?;
+    for shard in &parity {
+        archive_file.write_all(shard).map_err(|e| format!("Unable to write parity data: {}", e))?;
+    }
+    archive_file.write_all(&info_bytes).map_err(|e| format!("Unable to write parity info: {}", e))?;
+
+    Ok(parity_info)
+}
+
+/// Reads the ParityInfo whose trailer starts at trailer_start, if it is intact
+fn read_parity_info_at(archive : &[u8], trailer_start : usize) -> Option<ParityInfo>
+{
+    let trailer = ParityTrailer::from_bytes(archive.get(trailer_start..)?)?;
+    if trailer.info_length > MAX_PARITY_INFO_LENGTH || trailer.info_length as usize > trailer_start {
+        return None;
+    }
+
+    let info_bytes = &archive[trailer_start - trailer.info_length as usize..trailer_start];
+    if crc32fast::hash(info_bytes) != trailer.info_checksum {
+        return None;
+    }
+
+    let parity_info : ParityInfo = bincode::deserialize(info_bytes).ok()?;
+    let num_shards = parity_info.data_shards.checked_add(parity_info.parity_shards)?;
+    let shards_are_valid = parity_info.data_shards == PARITY_DATA_SHARDS &&
+        parity_info.parity_shards > 0 && num_shards <= 256 &&
+        parity_info.shard_checksums.len() == num_shards &&
+        parity_info.shard_size.checked_mul(PARITY_DATA_SHARDS as u64)? >= parity_info.data_length &&
+        parity_info.parity_shards_start >= parity_info.data_length;
+
+    if shards_are_valid { Some(parity_info) } else { None }
+}
+
+/// Finds an intact copy of the ParityInfo. The copy at the end of the file is tried first, then the file
+/// is searched (from the end) for the other copy, in case the end was truncated or damaged.
+fn find_parity_info(archive : &[u8]) -> Option<ParityInfo>
+{
+    if archive.len() >= PARITY_TRAILER_LENGTH {
+        if let Some(parity_info) = read_parity_info_at(archive, archive.len() - PARITY_TRAILER_LENGTH) {
+            return Some(parity_info);
+        }
+    }
+
+    (0..archive.len().saturating_sub(3)).rev()
+        .filter(|&i| archive[i..i + 4] == PARITY_MAGIC)
+        .find_map(|i| read_parity_info_at(archive, i))
+}
+
+/// What repair_archive found (and fixed)
+#[derive(Debug)]
+pub struct RepairReport {
+    pub damaged_data_shards : Vec<usize>,
+    pub damaged_parity_shards : Vec<usize>,
+    pub shard_size : u64,
+}
+
+/// Checks every shard of the archive against its checksum, and rebuilds any damaged shards from the parity
+/// data. The repaired archive atomically replaces the damaged one. Fails without changing the file if the
+/// archive has no intact parity section, or more shards are damaged than can be rebuilt.
+pub fn repair_archive(archive_path : &str) -> Result<RepairReport, String>
+{
+    let archive = fs::read(archive_path).map_err(|e| format!("Unable to read {}: {}", archive_path, e))?;
+    let parity_info = find_parity_info(&archive).ok_or_else(|| "Archive has no parity section, or it is too damaged to use".to_string())?;
+
+    let shard_size = parity_info.shard_size as usize;
+    let data_end = std::cmp::min(parity_info.data_length as usize, archive.len());
+    let parity_start = std::cmp::min(parity_info.parity_shards_start as usize, archive.len());
+    let mut shards = split_into_shards(&archive[..data_end], shard_size, parity_info.data_shards);
+    //in a small archive the last data shards can be entirely padding, which was never written to the file
+    for (shard_i, shard) in shards.iter_mut().enumerate() {
+        if (shard_i * shard_size) as u64 >= parity_info.data_length {
+            *shard = Some(vec![0u8; shard_size]);
+        }
+    }
+    shards.extend(split_into_shards(&archive[parity_start..], shard_size, parity_info.parity_shards));
+
+    let mut damaged_data_shards = Vec::new();
+    let mut damaged_parity_shards = Vec::new();
+    for (shard_i, shard) in shards.iter_mut().enumerate() {
+        let is_intact = match shard {
+            Some(shard_data) => crc32fast::hash(shard_data) == parity_info.shard_checksums[shard_i],
+            None => false,
+        };
+        if !is_intact {
+            *shard = None;
+            if shard_i < parity_info.data_shards {
+                damaged_data_shards.push(shard_i);
+            }
+            else {
+                damaged_parity_shards.push(shard_i - parity_info.data_shards);
+            }
+        }
+    }
+
+    let report = RepairReport {
+        damaged_data_shards,
+        damaged_parity_shards,
+        shard_size : parity_info.shard_size,
+    };
+    let num_damaged = report.damaged_data_shards.len() + report.damaged_parity_shards.len();
+    let end_is_intact = archive.len() as u64 == parity_info.parity_shards_start + (shard_size * parity_info.parity_shards) as u64 + (parity_info.parity_shards_start - parity_info.data_length);
+    if num_damaged == 0 && end_is_intact {
+        return Ok(report);
+    }
+    if num_damaged > parity_info.parity_shards {
+        return Err(format!("{} of {} shards ({} each) are damaged, but only {} can be rebuilt - unable to repair",
+                           num_damaged, shards.len(), pretty_print_bytes(shard_size as f64), parity_info.parity_shards));
+    }
+
+    let reed_solomon = ReedSolomon::new(parity_info.data_shards, parity_info.parity_shards).map_err(|e| format!("Invalid parity settings: {:?}", e))?;
+    reed_solomon.reconstruct(&mut shards).map_err(|e| format!("Unable to rebuild damaged shards: {:?}", e))?;
+
+    //put the archive back together, rewriting the whole parity section
+    let mut repaired_archive : Vec<u8> = shards[..parity_info.data_shards].iter().flat_map(|shard| shard.as_ref().unwrap().iter().cloned()).collect();
+    repaired_archive.truncate(parity_info.data_length as usize);
+    let info_bytes = parity_info_with_trailer(&parity_info);
+    repaired_archive.extend_from_slice(&info_bytes);
+    for shard in &shards[parity_info.data_shards..] {
+        repaired_archive.extend_from_slice(shard.as_ref().unwrap());
+    }
+    repaired_archive.extend_from_slice(&info_bytes);
+
+    //the same way compression writes the archive, so a failed repair never leaves a half written file
+    let temp_archive_path = TemporaryArchivePath::new(Path::new(archive_path));
+    {
+        let mut repaired_file = fs::File::create(&temp_archive_path.path)
+            .map_err(|e| format!("Unable to create {}: {}", temp_archive_path.path.display(), e))?;
+        repaired_file.write_all(&repaired_archive).map_err(|e| format!("Unable to write repaired archive: {}", e))?;
+        repaired_file.sync_all().map_err(|e| format!("Unable to flush repaired archive to disk: {}", e))?;
+    }
+    temp_archive_path.persist()?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+
+    use common::ScratchFolder;
+    use super::{append_parity, repair_archive, ParityInfo};
+
+    /// An archive-sized run of bytes with a parity section of parity_shards, saved in the scratch folder.
+    /// Returns the path, the intact archive and the parity info.
+    fn archive_with_parity(scratch_folder : &ScratchFolder, parity_shards : usize) -> (String, Vec<u8>, ParityInfo)
+    {
+        let data : Vec<u8> = (0..10_000u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+        let mut archive = Cursor::new(data.clone());
+        let parity_info = append_parity(&mut archive, data.len() as u64, parity_shards).unwrap();
+
+        let archive_path = scratch_folder.path.join("compressed_images.brotli");
+        fs::write(&archive_path, archive.get_ref()).unwrap();
+        (archive_path.to_str().unwrap().to_string(), archive.into_inner(), parity_info)
+    }
+
+    #[test]
+    fn damaged_shards_are_rebuilt()
+    {
+        let scratch_folder = ScratchFolder::new().unwrap();
+        let (archive_path, original, parity_info) = archive_with_parity(&scratch_folder, 4);
+
+        //one byte of data shard 3 and one byte of parity shard 1
+        let mut damaged = original.clone();
+        damaged[3 * parity_info.shard_size as usize + 1] ^= 0xFF;
+        damaged[(parity_info.parity_shards_start + parity_info.shard_size) as usize + 5] ^= 0xFF;
+        fs::write(&archive_path, &damaged).unwrap();
+
+        let report = repair_archive(&archive_path).unwrap();
+        assert_eq!(report.damaged_data_shards, vec![3]);
+        assert_eq!(report.damaged_parity_shards, vec![1]);
+        assert!(fs::read(&archive_path).unwrap() == original);
+    }
+
+    #[test]
+    fn too_much_damage_leaves_the_archive_alone()
+    {
+        let scratch_folder = ScratchFolder::new().unwrap();
+        let (archive_path, original, parity_info) = archive_with_parity(&scratch_folder, 4);
+
+        //one more damaged shard than there are parity shards
+        let mut damaged = original.clone();
+        for shard_i in 0..5 {
+            damaged[shard_i * parity_info.shard_size as usize] ^= 0xFF;
+        }
+        fs::write(&archive_path, &damaged).unwrap();
+
+        assert!(repair_archive(&archive_path).is_err());
+        assert!(fs::read(&archive_path).unwrap() == damaged);
+        assert_eq!(fs::read_dir(&scratch_folder.path).unwrap().count(), 1);
+    }
+}
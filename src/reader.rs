@@ -0,0 +1,178 @@
+//standard uses
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Mutex;
+
+//non-standard use
+use bincode;
+use bincode::Options;
+use image::RgbaImage;
+
+use common::{ArchiveHeader, DecompressionInfo, DecompressionInfoV2};
+use common::FILE_FORMAT_HEADER_LENGTH;
+use common::ReferenceTransform;
+use bitmap_coder::BitmapStreamReader;
+use codec::{Codec, new_decompressor};
+use compress::CropRegion;
+use strategy::strategy_by_id;
+use validate::{ArchiveLimits, validate_decompression_info};
+
+/// Reads and parses the fixed size header at the start of the archive
+pub fn read_archive_header<R : Read>(reader : &mut R) -> Result<ArchiveHeader, String>
+{
+    let mut header_bytes : [u8; FILE_FORMAT_HEADER_LENGTH] = [0; FILE_FORMAT_HEADER_LENGTH];
+    reader.read_exact(&mut header_bytes).map_err(|e| format!("Unable to read archive header: {}", e))?;
+    ArchiveHeader::from_bytes(&header_bytes)
+}
+
+/// Deserializes the (already decompressed) DecompressionInfo of an archive of the given format version.
+/// Allocations are limited to the metadata size limit.
+pub fn deserialize_decompression_info(raw_decompression_info : &[u8], version : u8, limits : &ArchiveLimits) -> Result<DecompressionInfo, String>
+{
+    if raw_decompression_info.len() as u64 > limits.max_metadata_bytes {
+        return Err(format!("Metadata is larger than the limit of {} bytes", limits.max_metadata_bytes));
+    }
+
+    //bincode::deserialize uses fixint encoding and allows trailing bytes - keep that, but limit allocations to the metadata size
+    let options = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limits.max_metadata_bytes);
+
+    //versions before 3 have no encoding strategy for each image
+    if version < 3 {
+        options.deserialize::<DecompressionInfoV2>(raw_decompression_info).map(DecompressionInfo::from)
+    } else {
+        options.deserialize(raw_decompression_info)
+    }.map_err(|e| format!("Unable to deserialize metadata: {}", e))
+}
+
+/// Decompresses and deserializes the DecompressionInfo, starting from the reader's current position
+pub fn read_decompression_info<R : Read>(reader : R, codec : Codec, version : u8, limits : &ArchiveLimits) -> Result<DecompressionInfo, String>
+{
+    //read at most one byte more than the limit, so an oversized metadata section can be detected without reading all of it
+    let decompression_info_decompressor = new_decompressor(reader, codec, limits.max_window_log)?;
+    let mut raw_decompression_info = Vec::new();
+    decompression_info_decompressor.take(limits.max_metadata_bytes + 1).read_to_end(&mut raw_decompression_info)
+        .map_err(|e| format!("Unable to decompress metadata: {}", e))?;
+
+    deserialize_decompression_info(&raw_decompression_info, version, limits)
+}
+
+/// The compressed streams of one solid block, read fully into memory
+pub struct SolidBlockData {
+    pub image_data : Vec<u8>,
+    pub bitmap : Vec<u8>,
+}
+
+/// Reads an archive from any seekable source (a file, or an in-memory buffer).
+/// The header and metadata are read and validated when the reader is created, so the offsets and sizes in
+/// `decompression_info` can be trusted. The reader is behind a mutex so blocks can be read from several threads.
+pub struct ArchiveReader<R> {
+    reader : Mutex<R>,
+    pub header : ArchiveHeader,
+    pub decompression_info : DecompressionInfo,
+    pub limits : ArchiveLimits,
+}
+
+impl<R : Read + Seek> ArchiveReader<R> {
+    pub fn new(mut reader : R, limits : &ArchiveLimits) -> Result<ArchiveReader<R>, String>
+    {
+        let file_length = reader.seek(SeekFrom::End(0)).map_err(|e| format!("Unable to read archive size: {}", e))?;
+        reader.seek(SeekFrom::Start(0)).map_err(|e| format!("Unable to seek to archive header: {}", e))?;
+
+        //determine where the decompression info starts, and which codec each stream uses
+        let header = read_archive_header(&mut reader)?;
+        let metadata_end = header.metadata_end(file_length);
+        if header.metadata_start > metadata_end || metadata_end > file_length {
+            return Err(format!("Metadata ({} to {}) is not inside the archive (length {})", header.metadata_start, metadata_end, file_length));
+        }
+
+        //Skip to the decompression information section, and deserialize (stopping before any parity data)
+        reader.seek(SeekFrom::Start(header.metadata_start)).map_err(|e| format!("Unable to seek to metadata: {}", e))?;
+        let decompression_info = read_decompression_info((&mut reader).take(metadata_end - header.metadata_start), header.metadata_codec, header.version, limits)?;
+
+        //check every offset and size in the metadata before trusting any of them
+        validate_decompression_info(&decompression_info, &header, file_length, limits)?;
+
+        Ok(ArchiveReader {
+            reader : Mutex::new(reader),
+            header,
+            decompression_info,
+            limits : *limits,
+        })
+    }
+
+    /// Reads the compressed image data and bitmap of one solid block into memory
+    pub fn read_solid_block(&self, block_i : usize) -> Result<SolidBlockData, String>
+    {
+        let block = &self.decompression_info.blocks[block_i];
+        let mut image_data = vec![0u8; (block.bitmap_data_start - block.image_data_start) as usize];
+        let mut bitmap = vec![0u8; (block.block_end - block.bitmap_data_start) as usize];
+
+        let mut reader = self.reader.lock().map_err(|_| "Archive reader was poisoned by another thread".to_string())?;
+        reader.seek(SeekFrom::Start(block.image_data_start)).map_err(|e| format!("Unable to seek to solid block {}: {}", block_i, e))?;
+        reader.read_exact(&mut image_data).map_err(|e| format!("Unable to read image data of solid block {}: {}", block_i, e))?;
+        reader.read_exact(&mut bitmap).map_err(|e| format!("Unable to read bitmap of solid block {}: {}", block_i, e))?;
+
+        Ok(SolidBlockData {
+            image_data,
+            bitmap,
+        })
+    }
+
+    /// Rebuilds every image in one solid block. on_image is called with the index (into images_info)
+    /// and pixels of each image, in order. Duplicates have no data in the streams, so they are skipped.
+    pub fn decode_solid_block<F>(&self, block_i : usize, block_data : &SolidBlockData, debug_mode : bool, mut on_image : F) -> Result<(), String>
+    where F : FnMut(usize, &RgbaImage)
+    {
+        let block = &self.decompression_info.blocks[block_i];
+        let mut bitmap_stream = BitmapStreamReader::new(&block_data.bitmap, &self.header, self.limits.max_window_log)?;
+        let mut image_data_decompressor = new_decompressor(&block_data.image_data[..], self.header.image_codec, self.limits.max_window_log)?;
+
+        //for each image
+        let mut prev_image = RgbaImage::new(0,0); //on first image iteration, this should never get accessed
+        //the image stored before prev_image, kept because a bidirectional image (B-frame) is stored after the image
+        //it is predicted from, and is predicted from this one as well
+        let mut image_before_prev = RgbaImage::new(0,0);
+        for img_i in block.first_image..block.first_image + block.num_images
+        {
+            let metadata = &self.decompression_info.images_info[img_i];
+            if metadata.duplicate_of.is_some() {
+                continue;
+            }
+
+            if debug_mode { println!("meta: {:?}", metadata); }
+
+            let strategy = strategy_by_id(metadata.strategy)?;
+            let diff_region = CropRegion { top_left : (metadata.x, metadata.y), dimensions : (metadata.diff_width, metadata.diff_height) };
+            let bitmap = bitmap_stream.read_image((metadata.output_width, metadata.output_height), &diff_region, strategy.scan_order(self.header.scan_order, diff_region.dimensions), strategy.is_bidirectional())
+                .map_err(|e| format!("Unable to read bitmap of image {} ({}): {}", img_i, metadata.output_path, e))?;
+
+            //flip/rotate the previous image if the compressor found that was a better match (the untransformed one
+            //is still needed if the next image is a B-frame)
+            let transformed_prev_image = if metadata.reference_transform != ReferenceTransform::None {
+                Some(metadata.reference_transform.apply(&prev_image))
+            }
+            else {
+                None
+            };
+
+            //reconstruct the image
+            let full_image = if strategy.is_bidirectional() {
+                strategy.decode(metadata, &image_before_prev, Some(&prev_image), self.header.scan_order, &mut image_data_decompressor, &mut &bitmap[..])
+            }
+            else {
+                strategy.decode(metadata, transformed_prev_image.as_ref().unwrap_or(&prev_image), None, self.header.scan_order, &mut image_data_decompressor, &mut &bitmap[..])
+            }.map_err(|e| format!("Unable to decode image {} ({}) with strategy {}: {}", img_i, metadata.output_path, strategy.name(), e))?;
+
+            on_image(img_i, &full_image);
+
+            //B-frames aren't the reference for anything
+            if !strategy.is_bidirectional() {
+                image_before_prev = std::mem::replace(&mut prev_image, full_image);
+            }
+        }
+
+        Ok(())
+    }
+}
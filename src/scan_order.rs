@@ -0,0 +1,503 @@
+//standard uses
+use std;
+use std::fmt;
+
+/// The order the pixels of a diff rectangle are visited in, when writing the bitmap and image data streams and when
+/// reading them back. It makes a difference to the compression ratio: orders which keep nearby pixels together let
+/// the codec find more repeated runs. The order is stored in the archive header, so the extractor visits the pixels
+/// in the same order as the compressor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanOrder {
+    //square blocks of the given size, each scanned row by row, with the blocks also scanned row by row (the original order)
+    Blocks(u16),
+    //row by row over the whole rectangle
+    Rows,
+    //row by row, alternating direction on every row (boustrophedon), so consecutive pixels are always neighbours
+    Snake,
+    //square blocks of the given size, in snake order within each block, with every other row of blocks right to left
+    SnakeBlocks(u16),
+    //a Hilbert curve generalized to any rectangle - consecutive pixels are neighbours, and nearby pixels stay close together
+    Hilbert,
+    //Z-order (Morton) curve - quadrants within quadrants
+    ZOrder,
+}
+
+/// The order used by archives from before the scan order was stored in the header (format version 3 and older)
+pub const DEFAULT_SCAN_ORDER : ScanOrder = ScanOrder::Blocks(50);
+
+impl ScanOrder {
+    pub fn id(&self) -> u8
+    {
+        match self {
+            ScanOrder::Blocks(_) => 0,
+            ScanOrder::Rows => 1,
+            ScanOrder::Snake => 2,
+            ScanOrder::SnakeBlocks(_) => 3,
+            ScanOrder::Hilbert => 4,
+            ScanOrder::ZOrder => 5,
+        }
+    }
+
+    /// The block size of the block orders, or 0 for the others
+    pub fn block_size(&self) -> u16
+    {
+        match self {
+            ScanOrder::Blocks(block_size) | ScanOrder::SnakeBlocks(block_size) => *block_size,
+            _ => 0,
+        }
+    }
+
+    pub fn from_id(id : u8, block_size : u16) -> Result<ScanOrder, String>
+    {
+        let scan_order = match id {
+            0 => ScanOrder::Blocks(block_size),
+            1 => ScanOrder::Rows,
+            2 => ScanOrder::Snake,
+            3 => ScanOrder::SnakeBlocks(block_size),
+            4 => ScanOrder::Hilbert,
+            5 => ScanOrder::ZOrder,
+            _ => return Err(format!("Unknown scan order id {}", id)),
+        };
+        scan_order.check_block_size()?;
+        Ok(scan_order)
+    }
+
+    /// Parses the command line form: blocks, blocks:N, rows, snake, snake-blocks, snake-blocks:N, hilbert or z-order.
+    /// The block orders use blocks of 50 pixels if no size is given.
+    pub fn from_name(name : &str) -> Result<ScanOrder, String>
+    {
+        let (kind, block_size) = match name.find(':') {
+            None => (name, None),
+            Some(colon) => {
+                let block_size = name[colon + 1..].parse::<u16>().map_err(|e| format!("Invalid block size in scan order '{}': {}", name, e))?;
+                (&name[..colon], Some(block_size))
+            },
+        };
+
+        let scan_order = match (kind, block_size) {
+            ("blocks", _) => ScanOrder::Blocks(block_size.unwrap_or(DEFAULT_SCAN_ORDER.block_size())),
+            ("snake-blocks", _) => ScanOrder::SnakeBlocks(block_size.unwrap_or(DEFAULT_SCAN_ORDER.block_size())),
+            ("rows", None) => ScanOrder::Rows,
+            ("snake", None) => ScanOrder::Snake,
+            ("hilbert", None) => ScanOrder::Hilbert,
+            ("z-order", None) => ScanOrder::ZOrder,
+            _ => return Err(format!("Unknown scan order '{}' (expected blocks[:N], rows, snake, snake-blocks[:N], hilbert or z-order)", name)),
+        };
+        scan_order.check_block_size()?;
+        Ok(scan_order)
+    }
+
+    fn check_block_size(&self) -> Result<(), String>
+    {
+        match self {
+            ScanOrder::Blocks(0) | ScanOrder::SnakeBlocks(0) => Err("Scan order block size must be at least 1".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// How the scan order is stored in the archive header: the id, then the block size (little endian)
+    pub fn to_bytes(&self) -> [u8; 3]
+    {
+        let block_size = self.block_size().to_le_bytes();
+        [self.id(), block_size[0], block_size[1]]
+    }
+
+    pub fn from_bytes(bytes : &[u8; 3]) -> Result<ScanOrder, String>
+    {
+        ScanOrder::from_id(bytes[0], u16::from_le_bytes([bytes[1], bytes[2]]))
+    }
+
+    /// Every (x, y) of a rectangle of the given size, in this order
+    pub fn iter(&self, dimensions : (u32, u32)) -> ScanIterator
+    {
+        let (width, height) = (dimensions.0 as usize, dimensions.1 as usize);
+        match self {
+            ScanOrder::Blocks(block_size) => ScanIterator::Blocks(BlockXYIterator::new(*block_size as usize, (width, height), false)),
+            ScanOrder::SnakeBlocks(block_size) => ScanIterator::Blocks(BlockXYIterator::new(*block_size as usize, (width, height), true)),
+            //a single block as wide as the rectangle
+            ScanOrder::Rows => ScanIterator::Blocks(BlockXYIterator::new(std::cmp::max(width, 1), (width, height), false)),
+            ScanOrder::Snake => ScanIterator::Blocks(BlockXYIterator::new(std::cmp::max(width, 1), (width, height), true)),
+            ScanOrder::Hilbert => ScanIterator::Hilbert(HilbertIterator::new(dimensions)),
+            ScanOrder::ZOrder => ScanIterator::ZOrder(ZOrderIterator::new(dimensions)),
+        }
+    }
+}
+
+impl Default for ScanOrder {
+    fn default() -> ScanOrder
+    {
+        DEFAULT_SCAN_ORDER
+    }
+}
+
+impl fmt::Display for ScanOrder {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            ScanOrder::Blocks(block_size) => write!(f, "blocks:{}", block_size),
+            ScanOrder::Rows => write!(f, "rows"),
+            ScanOrder::Snake => write!(f, "snake"),
+            ScanOrder::SnakeBlocks(block_size) => write!(f, "snake-blocks:{}", block_size),
+            ScanOrder::Hilbert => write!(f, "hilbert"),
+            ScanOrder::ZOrder => write!(f, "z-order"),
+        }
+    }
+}
+
+/// Iterates over the pixels of a rectangle in a ScanOrder. Every order is computed as it goes, as a diff rectangle
+/// can be as large as the largest image.
+pub enum ScanIterator {
+    Blocks(BlockXYIterator),
+    Hilbert(HilbertIterator),
+    ZOrder(ZOrderIterator),
+}
+
+impl Iterator for ScanIterator {
+    type Item = (u32, u32);
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        match self {
+            ScanIterator::Blocks(xy_iter) => xy_iter.next(),
+            ScanIterator::Hilbert(hilbert_iter) => hilbert_iter.next(),
+            ScanIterator::ZOrder(z_order_iter) => z_order_iter.next(),
+        }
+    }
+}
+
+/// Visits a rectangle in square blocks, row by row within each block and block by block across the rectangle.
+/// In snake mode, odd rows of pixels go right to left, as do odd rows of blocks. block_size must not be 0.
+pub struct BlockXYIterator {
+    block_size : usize,
+    dimensions : (usize, usize),
+    num_x_blocks : usize,
+    num_y_blocks : usize,
+    snake : bool,
+    i : usize,
+}
+
+impl BlockXYIterator {
+    pub fn new(block_size : usize, dimensions : (usize, usize), snake : bool) -> BlockXYIterator
+    {
+        let blocks_needed = |length : usize| length.div_ceil(block_size);
+
+        BlockXYIterator {
+            block_size,
+            dimensions,
+            //at least 1, to avoid a modulo (%) by zero. An empty rectangle has no rows of blocks, so no iterations.
+            num_x_blocks : std::cmp::max(blocks_needed(dimensions.0), 1),
+            num_y_blocks : if dimensions.0 == 0 { 0 } else { blocks_needed(dimensions.1) },
+            snake,
+            i : 0,
+        }
+    }
+}
+
+impl Iterator for BlockXYIterator {
+    type Item = (u32, u32);
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        let pixels_per_block = self.block_size * self.block_size;
+        loop {
+            let block_i = self.i / pixels_per_block;
+            let y_block = block_i / self.num_x_blocks;
+            if y_block >= self.num_y_blocks {
+                return None;
+            }
+
+            let mut x_block = block_i % self.num_x_blocks;
+            let mut x_in_block = self.i % self.block_size;
+            let y = y_block * self.block_size + (self.i / self.block_size) % self.block_size;
+            let reversed_row = self.snake && y % 2 == 1;
+            if self.snake && y_block % 2 == 1 {
+                x_block = self.num_x_blocks - 1 - x_block;
+            }
+            if reversed_row {
+                x_in_block = self.block_size - 1 - x_in_block;
+            }
+            let x = x_block * self.block_size + x_in_block;
+
+            if y >= self.dimensions.1 {
+                //below the rectangle (the last row of blocks is cut off) - skip the rest of the block
+                self.i = (block_i + 1) * pixels_per_block;
+            }
+            else if x >= self.dimensions.0 && !reversed_row {
+                //right of the rectangle (the last column of blocks is cut off) - skip the rest of this row of the block
+                self.i = (self.i / self.block_size + 1) * self.block_size;
+            }
+            else {
+                self.i += 1;
+                if x < self.dimensions.0 {
+                    return Some((x as u32, y as u32));
+                }
+            }
+        }
+    }
+}
+
+/// Part of a rectangle still to be visited by HilbertIterator: a corner, a major axis and a minor axis
+type HilbertRectangle = ((i64, i64), (i64, i64), (i64, i64));
+
+/// Visits a rectangle along a generalized Hilbert curve (Jakub Červený's "gilbert" algorithm). Every step is to
+/// a neighbouring pixel, except for a single diagonal step in some rectangles with odd sides.
+/// The curve is split into smaller rectangles as it goes, so only the rectangles still to be visited are kept.
+pub struct HilbertIterator {
+    //rectangles still to be visited, the next one last
+    pending : Vec<HilbertRectangle>,
+    //the straight line of pixels currently being visited: the next pixel, the step, and the number of pixels left
+    line : ((i64, i64), (i64, i64), i64),
+}
+
+impl HilbertIterator {
+    pub fn new(dimensions : (u32, u32)) -> HilbertIterator
+    {
+        let (width, height) = (dimensions.0 as i64, dimensions.1 as i64);
+        //the curve runs along the longer side
+        let pending = if width == 0 || height == 0 {
+            Vec::new()
+        }
+        else if width >= height {
+            vec![((0, 0), (width, 0), (0, height))]
+        }
+        else {
+            vec![((0, 0), (0, height), (width, 0))]
+        };
+
+        HilbertIterator {
+            pending,
+            line : ((0, 0), (0, 0), 0),
+        }
+    }
+
+    /// Splits the rectangle with corner start, major axis a and minor axis b into two (long rectangles) or three
+    /// (the usual Hilbert 'U') smaller rectangles, or starts a line if it is only one pixel thick
+    fn split(&mut self, start : (i64, i64), a : (i64, i64), b : (i64, i64))
+    {
+        let ((x, y), (ax, ay), (bx, by)) = (start, a, b);
+        let w = (ax + ay).abs();
+        let h = (bx + by).abs();
+        let (dax, day) = (ax.signum(), ay.signum());
+        let (dbx, dby) = (bx.signum(), by.signum());
+
+        if h == 1 {
+            self.line = ((x, y), (dax, day), w);
+            return;
+        }
+        if w == 1 {
+            self.line = ((x, y), (dbx, dby), h);
+            return;
+        }
+
+        //halves, rounded down
+        let (mut ax2, mut ay2) = (ax.div_euclid(2), ay.div_euclid(2));
+        let (mut bx2, mut by2) = (bx.div_euclid(2), by.div_euclid(2));
+        let w2 = (ax2 + ay2).abs();
+        let h2 = (bx2 + by2).abs();
+
+        //the parts are pushed last first, as the next rectangle is popped from the end
+        if 2 * w > 3 * h {
+            //long rectangle - split it in two along the major axis (preferring even lengths)
+            if w2 % 2 != 0 && w > 2 {
+                ax2 += dax;
+                ay2 += day;
+            }
+            self.pending.push(((x + ax2, y + ay2), (ax - ax2, ay - ay2), (bx, by)));
+            self.pending.push(((x, y), (ax2, ay2), (bx, by)));
+        }
+        else {
+            //up the near half of the minor axis, along the whole major axis, and back down the far side
+            if h2 % 2 != 0 && h > 2 {
+                bx2 += dbx;
+                by2 += dby;
+            }
+            self.pending.push(((x + (ax - dax) + (bx2 - dbx), y + (ay - day) + (by2 - dby)), (-bx2, -by2), (-(ax - ax2), -(ay - ay2))));
+            self.pending.push(((x + bx2, y + by2), (ax, ay), (bx - bx2, by - by2)));
+            self.pending.push(((x, y), (bx2, by2), (ax2, ay2)));
+        }
+    }
+}
+
+impl Iterator for HilbertIterator {
+    type Item = (u32, u32);
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        loop {
+            let ((x, y), (dx, dy), remaining) = self.line;
+            if remaining > 0 {
+                self.line = ((x + dx, y + dy), (dx, dy), remaining - 1);
+                return Some((x as u32, y as u32));
+            }
+
+            let (start, a, b) = self.pending.pop()?;
+            self.split(start, a, b);
+        }
+    }
+}
+
+/// Visits a rectangle along a Z-order curve: the curve over the enclosing power of two square, skipping the points
+/// outside the rectangle. Squares are split into quadrants as they are reached, and squares entirely outside the
+/// rectangle are skipped whole.
+pub struct ZOrderIterator {
+    dimensions : (u64, u64),
+    //squares still to be visited, the next one last. Each is its top left corner and its size.
+    pending : Vec<(u64, u64, u64)>,
+}
+
+impl ZOrderIterator {
+    pub fn new(dimensions : (u32, u32)) -> ZOrderIterator
+    {
+        let (width, height) = (dimensions.0 as u64, dimensions.1 as u64);
+        let pending = if width == 0 || height == 0 { Vec::new() } else { vec![(0, 0, std::cmp::max(width, height).next_power_of_two())] };
+
+        ZOrderIterator {
+            dimensions : (width, height),
+            pending,
+        }
+    }
+}
+
+impl Iterator for ZOrderIterator {
+    type Item = (u32, u32);
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        loop {
+            let (x, y, size) = self.pending.pop()?;
+            if x >= self.dimensions.0 || y >= self.dimensions.1 {
+                continue;
+            }
+            if size == 1 {
+                return Some((x as u32, y as u32));
+            }
+
+            //top left, top right, bottom left, bottom right - pushed last first
+            let half = size / 2;
+            self.pending.push((x + half, y + half, half));
+            self.pending.push((x, y + half, half));
+            self.pending.push((x + half, y, half));
+            self.pending.push((x, y, half));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use proptest::prelude::*;
+
+    use super::{BlockXYIterator, ScanOrder};
+
+    fn scan_order() -> impl Strategy<Value = ScanOrder>
+    {
+        prop_oneof![
+            (1u16..64).prop_map(ScanOrder::Blocks),
+            Just(ScanOrder::Rows),
+            Just(ScanOrder::Snake),
+            (1u16..64).prop_map(ScanOrder::SnakeBlocks),
+            Just(ScanOrder::Hilbert),
+            Just(ScanOrder::ZOrder),
+        ]
+    }
+
+    #[test]
+    fn block_xy_iterator_order()
+    {
+        //3x5 image in 2x2 blocks: each block is scanned row by row, and the blocks are scanned row by row
+        let expected = vec![(0, 0), (1, 0), (0, 1), (1, 1), (2, 0), (2, 1),
+                            (0, 2), (1, 2), (0, 3), (1, 3), (2, 2), (2, 3),
+                            (0, 4), (1, 4), (2, 4)];
+        assert_eq!(BlockXYIterator::new(2, (3, 5), false).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn snake_blocks_order()
+    {
+        //odd rows of pixels go right to left, and so does the second row of blocks
+        let expected = vec![(0, 0), (1, 0), (1, 1), (0, 1), (2, 0), (2, 1),
+                            (2, 2), (2, 3), (0, 2), (1, 2), (1, 3), (0, 3),
+                            (0, 4), (1, 4), (2, 4)];
+        assert_eq!(ScanOrder::SnakeBlocks(2).iter((3, 5)).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn z_order_order()
+    {
+        //2x2 squares of pixels, in the same order as the pixels within them
+        let expected = vec![(0, 0), (1, 0), (0, 1), (1, 1), (2, 0), (3, 0), (2, 1), (3, 1),
+                            (0, 2), (0, 3), (2, 2), (2, 3)];
+        assert_eq!(ScanOrder::ZOrder.iter((4, 4)).filter(|&(x, y)| y < 2 || x % 2 == 0).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn curves_are_computed_as_they_go()
+    {
+        //far too many points to compute up front
+        for scan_order in [ScanOrder::Hilbert, ScanOrder::ZOrder].iter() {
+            assert_eq!(scan_order.iter((1 << 20, 1 << 20)).take(4).count(), 4);
+        }
+    }
+
+    #[test]
+    fn block_xy_iterator_empty_image()
+    {
+        assert_eq!(BlockXYIterator::new(50, (0, 0), false).count(), 0);
+        assert_eq!(BlockXYIterator::new(50, (10, 0), false).count(), 0);
+        assert_eq!(BlockXYIterator::new(50, (0, 10), true).count(), 0);
+    }
+
+    #[test]
+    fn names_and_header_bytes_round_trip()
+    {
+        for name in ["blocks:50", "rows", "snake", "snake-blocks:7", "hilbert", "z-order"].iter() {
+            let scan_order = ScanOrder::from_name(name).unwrap();
+            assert_eq!(&scan_order.to_string(), name);
+            assert_eq!(ScanOrder::from_bytes(&scan_order.to_bytes()), Ok(scan_order));
+        }
+        assert_eq!(ScanOrder::from_name("blocks"), Ok(ScanOrder::Blocks(50)));
+        assert!(ScanOrder::from_name("blocks:0").is_err());
+        assert!(ScanOrder::from_name("rows:5").is_err());
+        assert!(ScanOrder::from_bytes(&[0, 0, 0]).is_err());
+        assert!(ScanOrder::from_bytes(&[200, 0, 0]).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn every_scan_order_visits_every_pixel_once(scan_order in scan_order(), width in 0u32..130, height in 0u32..130)
+        {
+            let points : Vec<(u32, u32)> = scan_order.iter((width, height)).collect();
+            let unique_points : HashSet<(u32, u32)> = points.iter().cloned().collect();
+
+            prop_assert_eq!(points.len(), width as usize * height as usize);
+            prop_assert_eq!(unique_points.len(), points.len());
+            prop_assert!(points.iter().all(|&(x, y)| x < width && y < height));
+        }
+
+        #[test]
+        fn block_xy_iterator_scans_blocks_in_order(block_size in 1usize..64, width in 1usize..130, height in 1usize..130)
+        {
+            //rows of blocks, then blocks within a row, then rows within a block, then pixels within a row
+            let scan_position = |&(x, y) : &(u32, u32)| {
+                let (x, y) = (x as usize, y as usize);
+                (y / block_size, x / block_size, y % block_size, x % block_size)
+            };
+
+            let points : Vec<(u32, u32)> = BlockXYIterator::new(block_size, (width, height), false).collect();
+            prop_assert!(points.windows(2).all(|pair| scan_position(&pair[0]) < scan_position(&pair[1])));
+        }
+
+        #[test]
+        fn snake_and_hilbert_only_step_to_neighbours(width in 1u32..130, height in 1u32..130)
+        {
+            let is_step = |a : (u32, u32), b : (u32, u32)| (a.0 as i64 - b.0 as i64).abs() + (a.1 as i64 - b.1 as i64).abs() == 1;
+            let snake : Vec<(u32, u32)> = ScanOrder::Snake.iter((width, height)).collect();
+            prop_assert!(snake.windows(2).all(|pair| is_step(pair[0], pair[1])));
+
+            //the generalized Hilbert curve may need a single diagonal step
+            let hilbert : Vec<(u32, u32)> = ScanOrder::Hilbert.iter((width, height)).collect();
+            prop_assert!(hilbert.windows(2).filter(|pair| !is_step(pair[0], pair[1])).count() <= 1);
+            prop_assert!(hilbert.windows(2).all(|pair| (pair[0].0 as i64 - pair[1].0 as i64).abs() <= 1 && (pair[0].1 as i64 - pair[1].1 as i64).abs() <= 1));
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//standard uses
+use std::fs;
+use std::path::Path;
+
+//non-standard use
+use serde_json;
+
+/// What compressing one image added to the archive
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ImageStats {
+    pub path : String,
+    pub width : u32,
+    pub height : u32,
+    pub solid_block : usize,
+    //the path of the earlier image this is an exact duplicate of (duplicates add nothing to the streams)
+    pub duplicate_of : Option<String>,
+    //name of the encoding strategy the image was stored with (empty for duplicates)
+    pub strategy : &'static str,
+    //percentage of the image's pixels which differ from the reference
+    pub diff_percent : f64,
+    pub crop_x : u32,
+    pub crop_y : u32,
+    pub crop_width : u32,
+    pub crop_height : u32,
+    pub changed_pixels : u64,
+    //uncompressed bytes written to the image data and bitmap streams
+    pub image_bytes : u64,
+    pub bitmap_bytes : u64,
+    //size of the image's image data and bitmap compressed on their own with a fast codec. The archive compresses
+    //them along with the rest of the block, so this is only an estimate (usually an overestimate)
+    pub estimated_compressed_bytes : u64,
+}
+
+/// Compressed size of each stream of one solid block. The codecs buffer their input, so the compressed size
+/// can only be measured per block, not per image.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct BlockStats {
+    pub num_images : usize,
+    pub image_bytes : u64,
+    pub bitmap_bytes : u64,
+    pub compressed_image_bytes : u64,
+    pub compressed_bitmap_bytes : u64,
+}
+
+/// Per-image statistics of one compression run, which can be saved as CSV or JSON
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct CompressionStats {
+    pub images : Vec<ImageStats>,
+    pub blocks : Vec<BlockStats>,
+}
+
+const CSV_HEADER : &str = "path,width,height,solid_block,duplicate_of,strategy,diff_percent,crop_x,crop_y,crop_width,crop_height,changed_pixels,image_bytes,bitmap_bytes,estimated_compressed_bytes";
+
+/// The file formats statistics can be saved in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsFormat {
+    Csv,
+    Json,
+}
+
+impl StatsFormat {
+    /// Picks the format from the file extension (.csv or .json)
+    pub fn from_path(path : &str) -> Result<StatsFormat, String>
+    {
+        match Path::new(path).extension().and_then(|extension| extension.to_str()).map(|extension| extension.to_lowercase()).as_deref() {
+            Some("csv") => Ok(StatsFormat::Csv),
+            Some("json") => Ok(StatsFormat::Json),
+            _ => Err(format!("Don't know which format to save statistics file '{}' in - use a .csv or .json extension", path)),
+        }
+    }
+}
+
+/// Quotes a CSV field if it contains a separator, quote or line break
+fn csv_field(value : &str) -> String
+{
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    }
+    else {
+        value.to_string()
+    }
+}
+
+impl CompressionStats {
+    /// One line per image, with a header line
+    pub fn to_csv(&self) -> String
+    {
+        let mut csv = String::from(CSV_HEADER);
+        csv.push('\n');
+        for image in &self.images {
+            csv.push_str(&format!("{},{},{},{},{},{},{:.3},{},{},{},{},{},{},{},{}\n",
+                csv_field(&image.path), image.width, image.height, image.solid_block,
+                csv_field(image.duplicate_of.as_deref().unwrap_or("")), image.strategy, image.diff_percent,
+                image.crop_x, image.crop_y, image.crop_width, image.crop_height,
+                image.changed_pixels, image.image_bytes, image.bitmap_bytes, image.estimated_compressed_bytes));
+        }
+        csv
+    }
+
+    pub fn to_json(&self) -> String
+    {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn save(&self, path : &str, format : StatsFormat) -> Result<(), String>
+    {
+        let contents = match format {
+            StatsFormat::Csv => self.to_csv(),
+            StatsFormat::Json => self.to_json(),
+        };
+        fs::write(path, contents).map_err(|e| format!("Unable to write statistics to {}: {}", path, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompressionStats, ImageStats, StatsFormat, CSV_HEADER};
+
+    #[test]
+    fn csv_quotes_awkward_paths()
+    {
+        let stats = CompressionStats {
+            images : vec![
+                ImageStats { path : "a.png".to_string(), width : 2, height : 3, strategy : "delta", diff_percent : 50.0, changed_pixels : 3, image_bytes : 12, bitmap_bytes : 6, estimated_compressed_bytes : 9, ..ImageStats::default() },
+                ImageStats { path : "b, \"c\".png".to_string(), width : 2, height : 3, duplicate_of : Some("a.png".to_string()), ..ImageStats::default() },
+            ],
+            blocks : Vec::new(),
+        };
+
+        let csv = stats.to_csv();
+        let lines : Vec<&str> = csv.lines().collect();
+        assert_eq!(lines, vec![
+            CSV_HEADER,
+            "a.png,2,3,0,,delta,50.000,0,0,0,0,3,12,6,9",
+            "\"b, \"\"c\"\".png\",2,3,0,a.png,,0.000,0,0,0,0,0,0,0,0",
+        ]);
+    }
+
+    #[test]
+    fn format_is_picked_from_extension()
+    {
+        assert_eq!(StatsFormat::from_path("out/Stats.CSV"), Ok(StatsFormat::Csv));
+        assert_eq!(StatsFormat::from_path("stats.json"), Ok(StatsFormat::Json));
+        assert!(StatsFormat::from_path("stats.txt").is_err());
+    }
+}
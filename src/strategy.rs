@@ -0,0 +1,597 @@
+//standard uses
+use std::io::{Read, Write};
+
+//non-standard use
+use image;
+use image::{Rgba, RgbaImage};
+
+use common::CompressedImageInfo;
+use common::{get_offset_to_other_image, try_get_pixel};
+use compress::{CropRegion, Cropper};
+use scan_order::{ScanIterator, ScanOrder};
+
+/// A way of encoding an image as the difference from a reference image (the previous image, after any
+/// flip/rotation). Each image in the archive records the id of the strategy it was encoded with, and the
+/// extractor looks the strategy up in STRATEGIES to decode it.
+///
+/// To add a strategy, implement this trait and add it to STRATEGIES with an unused id. It can then be picked by
+/// --auto and is covered by the round trip tests, without any changes to the archive format or the command line.
+/// Ids are stored in archives, so an id must never be reused for a different encoding.
+pub trait DiffStrategy : Sync {
+    fn id(&self) -> u8;
+
+    fn name(&self) -> &'static str;
+
+    /// Whether the reference image is used at all (if not, the compressor doesn't bother flipping/rotating it)
+    fn uses_reference(&self) -> bool
+    {
+        true
+    }
+
+    /// Whether the image is a B-frame, predicted from the next stored image as well as the previous one. These
+    /// are only picked by the compressor's bidirectional search (see plan_bidirectional_frames), which stores the
+    /// next image first, and they aren't used as the reference for any other image.
+    fn is_bidirectional(&self) -> bool
+    {
+        false
+    }
+
+    /// The order the bitmap bytes cover a diff rectangle of the given size, so debug-dump can draw the bitmap.
+    /// archive_scan_order is the scan order stored in the archive header, which strategies may use or ignore.
+    fn scan_order(&self, archive_scan_order : ScanOrder, dimensions : (u32, u32)) -> ScanIterator;
+
+    /// Writes whatever is needed to rebuild image from reference_image (and next_image, for bidirectional
+    /// strategies) to the image data and bitmap streams. The bitmap must be one 0 or 1 byte for each pixel of the
+    /// diff rectangle, in scan_order, so the bitmap coder can model it - bidirectional strategies may also use 2.
+    /// Returns the diff rectangle (which is stored in the image's metadata) and the number of pixels stored in the
+    /// image data.
+    fn encode(&self, image : &RgbaImage, reference_image : &RgbaImage, next_image : Option<&RgbaImage>, archive_scan_order : ScanOrder, image_data : &mut dyn Write, bitmap : &mut dyn Write) -> (CropRegion, u64);
+
+    /// Rebuilds an image from its metadata, the reference image(s) and the streams, reading exactly what encode
+    /// wrote. The metadata has been validated, so the diff rectangle is inside the image.
+    fn decode(&self, info : &CompressedImageInfo, reference_image : &RgbaImage, next_image : Option<&RgbaImage>, archive_scan_order : ScanOrder, image_data : &mut dyn Read, bitmap : &mut dyn Read) -> Result<RgbaImage, String>;
+}
+
+/// How the pixels which differ from the reference are stored in the image data stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Predictor {
+    //the pixel itself
+    Raw,
+    //the difference (per channel, wrapping) from the reference pixel, or the pixel itself where there is no
+    //reference pixel. Helps when an area changes colour or brightness without changing shape.
+    Delta,
+}
+
+impl Predictor {
+    /// The bytes stored for pixel, given the reference pixel at the same place (if any)
+    pub fn encode(&self, pixel : Rgba<u8>, reference_pixel : Option<Rgba<u8>>) -> [u8; 4]
+    {
+        match (self, reference_pixel) {
+            (Predictor::Delta, Some(reference_pixel)) => [
+                pixel[0].wrapping_sub(reference_pixel[0]),
+                pixel[1].wrapping_sub(reference_pixel[1]),
+                pixel[2].wrapping_sub(reference_pixel[2]),
+                pixel[3].wrapping_sub(reference_pixel[3]),
+            ],
+            _ => pixel.0,
+        }
+    }
+
+    /// Undoes encode
+    pub fn decode(&self, stored : [u8; 4], reference_pixel : Option<Rgba<u8>>) -> Rgba<u8>
+    {
+        match (self, reference_pixel) {
+            (Predictor::Delta, Some(reference_pixel)) => Rgba([
+                stored[0].wrapping_add(reference_pixel[0]),
+                stored[1].wrapping_add(reference_pixel[1]),
+                stored[2].wrapping_add(reference_pixel[2]),
+                stored[3].wrapping_add(reference_pixel[3]),
+            ]),
+            _ => Rgba(stored),
+        }
+    }
+}
+
+/// The original spritezip encoding, and variations of it. The reference is lined up bottom-center with the image,
+/// and the rectangle around every different pixel is the diff rectangle. For each pixel of the diff rectangle (in
+/// the strategy's scan order, or the archive's if it doesn't have one), the bitmap holds 1 if it differs from the
+/// reference, and 0 if not. The image data holds the
+/// different pixels, as given by the predictor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockDiffStrategy {
+    pub id : u8,
+    pub name : &'static str,
+    //the order the diff rectangle is scanned in, or None to use the archive's scan order
+    pub scan_order : Option<ScanOrder>,
+    pub predictor : Predictor,
+    //ignore the previous image and store every pixel (the image is still the reference for the next one)
+    pub keyframe : bool,
+}
+
+/// What every image used before strategies were added
+pub static BASELINE_STRATEGY : BlockDiffStrategy = BlockDiffStrategy {
+    id : 0,
+    name : "baseline",
+    scan_order : None,
+    predictor : Predictor::Raw,
+    keyframe : false,
+};
+
+static SMALL_BLOCKS_STRATEGY : BlockDiffStrategy = BlockDiffStrategy { id : 1, name : "small-blocks", scan_order : Some(ScanOrder::Blocks(16)), predictor : Predictor::Raw, keyframe : false };
+static ROWS_STRATEGY : BlockDiffStrategy = BlockDiffStrategy { id : 2, name : "rows", scan_order : Some(ScanOrder::Rows), predictor : Predictor::Raw, keyframe : false };
+static DELTA_STRATEGY : BlockDiffStrategy = BlockDiffStrategy { id : 3, name : "delta", scan_order : None, predictor : Predictor::Delta, keyframe : false };
+static DELTA_ROWS_STRATEGY : BlockDiffStrategy = BlockDiffStrategy { id : 4, name : "delta-rows", scan_order : Some(ScanOrder::Rows), predictor : Predictor::Delta, keyframe : false };
+static KEYFRAME_STRATEGY : BlockDiffStrategy = BlockDiffStrategy { id : 5, name : "keyframe", scan_order : None, predictor : Predictor::Raw, keyframe : true };
+static MOTION_STRATEGY : MotionStrategy = MotionStrategy { id : 6, name : "motion", tile_size : 16, search_radius : 8 };
+pub static BIDIRECTIONAL_STRATEGY : BidirectionalStrategy = BidirectionalStrategy { id : 7, name : "bidirectional" };
+
+/// Every strategy which can be used in an archive
+pub static STRATEGIES : [&dyn DiffStrategy; 8] = [
+    &BASELINE_STRATEGY,
+    &SMALL_BLOCKS_STRATEGY,
+    &ROWS_STRATEGY,
+    &DELTA_STRATEGY,
+    &DELTA_ROWS_STRATEGY,
+    &KEYFRAME_STRATEGY,
+    &MOTION_STRATEGY,
+    &BIDIRECTIONAL_STRATEGY,
+];
+
+pub fn strategy_by_id(id : u8) -> Result<&'static dyn DiffStrategy, String>
+{
+    STRATEGIES.iter().find(|strategy| strategy.id() == id).cloned().ok_or_else(|| format!("Unknown encoding strategy id {}", id))
+}
+
+pub fn strategy_by_name(name : &str) -> Result<&'static dyn DiffStrategy, String>
+{
+    STRATEGIES.iter().find(|strategy| strategy.name() == name).cloned().ok_or_else(|| {
+        let names : Vec<&str> = STRATEGIES.iter().map(|strategy| strategy.name()).collect();
+        format!("Unknown encoding strategy '{}' (expected one of {})", name, names.join(", "))
+    })
+}
+
+/// The rectangle around every pixel of image which differs from the reference (lined up bottom-center), and the
+/// number of different pixels
+fn crop_to_differences(image : &RgbaImage, reference_image : &RgbaImage) -> (CropRegion, u64)
+{
+    let (x_offset_to_reference, y_offset_to_reference) = get_offset_to_other_image(image, reference_image);
+    let mut cropper = Cropper::new((image.width(), image.height()));
+    let mut difference_count = 0;
+    for (x, y, pixel) in image.enumerate_pixels() {
+        if try_get_pixel((x as i64 + x_offset_to_reference, y as i64 + y_offset_to_reference), reference_image) != Some(*pixel) {
+            cropper.add_nonzero_pixel(x, y);
+            difference_count += 1;
+        }
+    }
+    (cropper.get_crop_region(), difference_count)
+}
+
+/// An image of the given size with the reference copied in (lined up bottom-center), and transparent black where
+/// there is no reference pixel
+fn copy_reference(image_size : (u32, u32), reference_image : &RgbaImage) -> RgbaImage
+{
+    let mut image = RgbaImage::new(image_size.0, image_size.1);
+    let (x_offset_to_reference, y_offset_to_reference) = get_offset_to_other_image(&image, reference_image);
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        if let Some(reference_pixel) = try_get_pixel((x as i64 + x_offset_to_reference, y as i64 + y_offset_to_reference), reference_image) {
+            *pixel = reference_pixel;
+        }
+    }
+    image
+}
+
+impl DiffStrategy for BlockDiffStrategy {
+    fn id(&self) -> u8
+    {
+        self.id
+    }
+
+    fn name(&self) -> &'static str
+    {
+        self.name
+    }
+
+    fn uses_reference(&self) -> bool
+    {
+        !self.keyframe
+    }
+
+    fn scan_order(&self, archive_scan_order : ScanOrder, dimensions : (u32, u32)) -> ScanIterator
+    {
+        self.scan_order.unwrap_or(archive_scan_order).iter(dimensions)
+    }
+
+    fn encode(&self, original_image : &RgbaImage, prev_image : &RgbaImage, _next_image : Option<&RgbaImage>, archive_scan_order : ScanOrder, image_compressor : &mut dyn Write, bitmap_compressor : &mut dyn Write) -> (CropRegion, u64)
+    {
+        let null_image = RgbaImage::new(0, 0);
+        let prev_image = if self.keyframe { &null_image } else { prev_image };
+        let (x_offset_to_other_image , y_offset_to_other_image)= get_offset_to_other_image(original_image, prev_image);
+
+        // ----------------------------  DO CROP  ----------------------------
+        let (crop_region, difference_count) = crop_to_differences(original_image, prev_image);
+
+        //Get a cropped version of the image to work on
+        let cropped_image = image::imageops::crop_imm(original_image,
+        crop_region.top_left.0, crop_region.top_left.1,
+        crop_region.dimensions.0, crop_region.dimensions.1).to_image();
+
+        // ----------------------------  DO COMPRESS  ----------------------------
+        let mut difference : Vec<u8> = Vec::with_capacity(cropped_image.width() as usize * cropped_image.height() as usize);
+
+        for (x,y) in self.scan_order(archive_scan_order, cropped_image.dimensions())
+        {
+            let cropped_pixel = *cropped_image.get_pixel(x, y);
+            let original_image_x = x + crop_region.top_left.0;
+            let original_image_y = y + crop_region.top_left.1;
+            let prev_x = original_image_x as i64 + x_offset_to_other_image;
+            let prev_y = original_image_y as i64 + y_offset_to_other_image;
+
+            let prev_pixel = try_get_pixel((prev_x, prev_y), prev_image);
+            if prev_pixel == Some(cropped_pixel) {
+                difference.push(0u8);
+            }
+            else {
+                difference.push(1u8);
+                image_compressor.write_all(&self.predictor.encode(cropped_pixel, prev_pixel)).unwrap();
+            }
+        }
+
+        bitmap_compressor.write_all(&difference).unwrap();
+
+        //return crop_region to be saved as metadata
+        (crop_region, difference_count)
+    }
+
+    fn decode(&self, metadata : &CompressedImageInfo, prev_image : &RgbaImage, _next_image : Option<&RgbaImage>, archive_scan_order : ScanOrder, image_data_decompressor : &mut dyn Read, bitmap_info_decompressor : &mut dyn Read) -> Result<RgbaImage, String>
+    {
+        let null_image = RgbaImage::new(0, 0);
+        let prev_image = if self.keyframe { &null_image } else { prev_image };
+
+        //take a slice which contains only the desired region
+        //read out the required number of bytes
+        let mut cropped_bitmap = vec![0u8; metadata.diff_width as usize * metadata.diff_height as usize];
+        bitmap_info_decompressor.read_exact(&mut cropped_bitmap).map_err(|e| format!("Unable to read bitmap: {}", e))?;
+
+        //reconstruct the image, starting from a copy of the previous one
+        let mut full_image = copy_reference((metadata.output_width, metadata.output_height), prev_image);
+        let (x_offset_to_prev_image , y_offset_to_prev_image)= get_offset_to_other_image(&full_image, prev_image);
+
+        //copy pixels which were different in the new image
+        let diff_xy_iter = self.scan_order(archive_scan_order, (metadata.diff_width, metadata.diff_height));
+        for ((x, y), &bitmap_value) in diff_xy_iter.zip(cropped_bitmap.iter()) {
+            //pixels are different - decompress a pixel from the compressed image data
+            if bitmap_value == 1 {
+                let mut pixel_raw_data = [0u8; 4];
+                image_data_decompressor.read_exact(&mut pixel_raw_data).map_err(|e| format!("Unable to read image data: {}", e))?;
+                let (image_x, image_y) = (x + metadata.x, y + metadata.y);
+                let prev_pixel = try_get_pixel((image_x as i64 + x_offset_to_prev_image, image_y as i64 + y_offset_to_prev_image), prev_image);
+                *full_image.get_pixel_mut(image_x, image_y) = self.predictor.decode(pixel_raw_data, prev_pixel);
+            }
+        }
+
+        Ok(full_image)
+    }
+}
+
+/// Block motion compensation, for sprites where a part (an arm, a weapon) moves between images without otherwise
+/// changing. The diff rectangle is found as for the baseline strategy, then split into tiles (anchored at its top
+/// left). Each tile gets the motion vector within search_radius which makes the most of its pixels match the
+/// reference shifted by that vector. The image data starts with the vectors (one signed byte each for x and y, for
+/// each tile in row-major order), and then holds the pixels which still differ; the bitmap holds 1 for those
+/// pixels and 0 for pixels which are taken from the shifted reference. The archive's scan order is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MotionStrategy {
+    pub id : u8,
+    pub name : &'static str,
+    pub tile_size : u32,
+    //the largest x or y component of a motion vector. Must fit in an i8.
+    pub search_radius : i8,
+}
+
+impl MotionStrategy {
+    fn tile_count(&self, dimensions : (u32, u32)) -> (u32, u32)
+    {
+        (dimensions.0.div_ceil(self.tile_size), dimensions.1.div_ceil(self.tile_size))
+    }
+
+    /// The vector which leaves the fewest pixels of the tile at tile_top_left (in image coordinates, clipped to the
+    /// diff rectangle) different from the shifted reference. Ties go to the earlier candidate, so (0, 0) is kept
+    /// unless moving actually helps.
+    fn find_motion_vector(&self, image : &RgbaImage, reference_image : &RgbaImage, offset_to_reference : (i64, i64), tile_top_left : (u32, u32), crop_region : &CropRegion) -> (i8, i8)
+    {
+        let crop_right = crop_region.top_left.0 + crop_region.dimensions.0;
+        let crop_bottom = crop_region.top_left.1 + crop_region.dimensions.1;
+        let tile_right = crop_right.min(tile_top_left.0 + self.tile_size);
+        let tile_bottom = crop_bottom.min(tile_top_left.1 + self.tile_size);
+
+        let radius = self.search_radius;
+        let candidates = Some((0, 0)).into_iter()
+            .chain((-radius..=radius).flat_map(|dy| (-radius..=radius).map(move |dx| (dx, dy))));
+
+        let mut best = ((0, 0), u64::MAX);
+        for (dx, dy) in candidates {
+            let mut mismatches = 0;
+            'tile: for y in tile_top_left.1..tile_bottom {
+                for x in tile_top_left.0..tile_right {
+                    let reference_xy = (x as i64 + offset_to_reference.0 + dx as i64, y as i64 + offset_to_reference.1 + dy as i64);
+                    if try_get_pixel(reference_xy, reference_image) != Some(*image.get_pixel(x, y)) {
+                        mismatches += 1;
+                        //can't beat the best vector any more
+                        if mismatches >= best.1 {
+                            break 'tile;
+                        }
+                    }
+                }
+            }
+            if mismatches < best.1 {
+                best = ((dx, dy), mismatches);
+                if mismatches == 0 {
+                    break;
+                }
+            }
+        }
+        best.0
+    }
+}
+
+impl DiffStrategy for MotionStrategy {
+    fn id(&self) -> u8
+    {
+        self.id
+    }
+
+    fn name(&self) -> &'static str
+    {
+        self.name
+    }
+
+    fn scan_order(&self, archive_scan_order : ScanOrder, dimensions : (u32, u32)) -> ScanIterator
+    {
+        archive_scan_order.iter(dimensions)
+    }
+
+    fn encode(&self, original_image : &RgbaImage, prev_image : &RgbaImage, _next_image : Option<&RgbaImage>, archive_scan_order : ScanOrder, image_compressor : &mut dyn Write, bitmap_compressor : &mut dyn Write) -> (CropRegion, u64)
+    {
+        let (crop_region, _) = crop_to_differences(original_image, prev_image);
+        let offset_to_reference = get_offset_to_other_image(original_image, prev_image);
+
+        // ----------------------------  FIND MOTION VECTORS  ----------------------------
+        let (tiles_x, tiles_y) = self.tile_count(crop_region.dimensions);
+        let mut motion_vectors = Vec::with_capacity(tiles_x as usize * tiles_y as usize);
+        for tile_y in 0..tiles_y {
+            for tile_x in 0..tiles_x {
+                let tile_top_left = (crop_region.top_left.0 + tile_x * self.tile_size, crop_region.top_left.1 + tile_y * self.tile_size);
+                let (dx, dy) = self.find_motion_vector(original_image, prev_image, offset_to_reference, tile_top_left, &crop_region);
+                image_compressor.write_all(&[dx as u8, dy as u8]).unwrap();
+                motion_vectors.push((dx, dy));
+            }
+        }
+
+        // ----------------------------  DO COMPRESS  ----------------------------
+        let mut difference = Vec::with_capacity(crop_region.dimensions.0 as usize * crop_region.dimensions.1 as usize);
+        let mut difference_count = 0;
+        for (x, y) in self.scan_order(archive_scan_order, crop_region.dimensions) {
+            let (dx, dy) = motion_vectors[((y / self.tile_size) * tiles_x + x / self.tile_size) as usize];
+            let (image_x, image_y) = (x + crop_region.top_left.0, y + crop_region.top_left.1);
+            let pixel = *original_image.get_pixel(image_x, image_y);
+            let reference_xy = (image_x as i64 + offset_to_reference.0 + dx as i64, image_y as i64 + offset_to_reference.1 + dy as i64);
+            if try_get_pixel(reference_xy, prev_image) == Some(pixel) {
+                difference.push(0u8);
+            }
+            else {
+                difference.push(1u8);
+                difference_count += 1;
+                image_compressor.write_all(&pixel.0).unwrap();
+            }
+        }
+
+        bitmap_compressor.write_all(&difference).unwrap();
+
+        (crop_region, difference_count)
+    }
+
+    fn decode(&self, metadata : &CompressedImageInfo, prev_image : &RgbaImage, _next_image : Option<&RgbaImage>, archive_scan_order : ScanOrder, image_data_decompressor : &mut dyn Read, bitmap_info_decompressor : &mut dyn Read) -> Result<RgbaImage, String>
+    {
+        let diff_dimensions = (metadata.diff_width, metadata.diff_height);
+        let (tiles_x, tiles_y) = self.tile_count(diff_dimensions);
+        let mut motion_vector_bytes = vec![0u8; 2 * tiles_x as usize * tiles_y as usize];
+        image_data_decompressor.read_exact(&mut motion_vector_bytes).map_err(|e| format!("Unable to read motion vectors: {}", e))?;
+        let motion_vectors : Vec<(i8, i8)> = motion_vector_bytes.chunks(2).map(|vector| (vector[0] as i8, vector[1] as i8)).collect();
+        if let Some(vector) = motion_vectors.iter().find(|(dx, dy)| dx.unsigned_abs() > self.search_radius as u8 || dy.unsigned_abs() > self.search_radius as u8) {
+            return Err(format!("Motion vector {:?} is outside the search radius {}", vector, self.search_radius));
+        }
+
+        let mut cropped_bitmap = vec![0u8; metadata.diff_width as usize * metadata.diff_height as usize];
+        bitmap_info_decompressor.read_exact(&mut cropped_bitmap).map_err(|e| format!("Unable to read bitmap: {}", e))?;
+
+        //start from a copy of the previous image, then fill in the diff rectangle
+        let mut full_image = copy_reference((metadata.output_width, metadata.output_height), prev_image);
+        let offset_to_reference = get_offset_to_other_image(&full_image, prev_image);
+
+        for ((x, y), &bitmap_value) in self.scan_order(archive_scan_order, diff_dimensions).zip(cropped_bitmap.iter()) {
+            let (image_x, image_y) = (x + metadata.x, y + metadata.y);
+            let pixel = if bitmap_value == 1 {
+                let mut pixel_raw_data = [0u8; 4];
+                image_data_decompressor.read_exact(&mut pixel_raw_data).map_err(|e| format!("Unable to read image data: {}", e))?;
+                Rgba(pixel_raw_data)
+            }
+            else {
+                let (dx, dy) = motion_vectors[((y / self.tile_size) * tiles_x + x / self.tile_size) as usize];
+                let reference_xy = (image_x as i64 + offset_to_reference.0 + dx as i64, image_y as i64 + offset_to_reference.1 + dy as i64);
+                try_get_pixel(reference_xy, prev_image)
+                    .ok_or_else(|| format!("Pixel ({}, {}) is moved from outside the previous image", image_x, image_y))?
+            };
+            *full_image.get_pixel_mut(image_x, image_y) = pixel;
+        }
+
+        Ok(full_image)
+    }
+}
+
+/// A B-frame: the image is predicted from the image before it and the image after it (which the compressor stores
+/// first). The diff rectangle is the baseline one, against the previous image. For each of its pixels (in the
+/// archive's scan order), the bitmap holds 0 if it is the same as the previous image, 2 if it is the same as the
+/// next image, and 1 if it is stored in the image data. Both images are lined up bottom-center, and aren't
+/// flipped or rotated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BidirectionalStrategy {
+    pub id : u8,
+    pub name : &'static str,
+}
+
+/// Bitmap value for a pixel taken from the next image
+const FROM_NEXT_IMAGE : u8 = 2;
+
+impl DiffStrategy for BidirectionalStrategy {
+    fn id(&self) -> u8
+    {
+        self.id
+    }
+
+    fn name(&self) -> &'static str
+    {
+        self.name
+    }
+
+    fn is_bidirectional(&self) -> bool
+    {
+        true
+    }
+
+    fn scan_order(&self, archive_scan_order : ScanOrder, dimensions : (u32, u32)) -> ScanIterator
+    {
+        archive_scan_order.iter(dimensions)
+    }
+
+    fn encode(&self, original_image : &RgbaImage, prev_image : &RgbaImage, next_image : Option<&RgbaImage>, archive_scan_order : ScanOrder, image_compressor : &mut dyn Write, bitmap_compressor : &mut dyn Write) -> (CropRegion, u64)
+    {
+        let next_image = next_image.expect("bidirectional strategy needs the next image");
+        let (crop_region, _) = crop_to_differences(original_image, prev_image);
+        let (x_offset_to_prev_image, y_offset_to_prev_image) = get_offset_to_other_image(original_image, prev_image);
+        let (x_offset_to_next_image, y_offset_to_next_image) = get_offset_to_other_image(original_image, next_image);
+
+        let mut difference = Vec::with_capacity(crop_region.dimensions.0 as usize * crop_region.dimensions.1 as usize);
+        let mut difference_count = 0;
+        for (x, y) in self.scan_order(archive_scan_order, crop_region.dimensions) {
+            let (image_x, image_y) = (x + crop_region.top_left.0, y + crop_region.top_left.1);
+            let pixel = Some(*original_image.get_pixel(image_x, image_y));
+            if try_get_pixel((image_x as i64 + x_offset_to_prev_image, image_y as i64 + y_offset_to_prev_image), prev_image) == pixel {
+                difference.push(0u8);
+            }
+            else if try_get_pixel((image_x as i64 + x_offset_to_next_image, image_y as i64 + y_offset_to_next_image), next_image) == pixel {
+                difference.push(FROM_NEXT_IMAGE);
+            }
+            else {
+                difference.push(1u8);
+                difference_count += 1;
+                image_compressor.write_all(&original_image.get_pixel(image_x, image_y).0).unwrap();
+            }
+        }
+
+        bitmap_compressor.write_all(&difference).unwrap();
+
+        (crop_region, difference_count)
+    }
+
+    fn decode(&self, metadata : &CompressedImageInfo, prev_image : &RgbaImage, next_image : Option<&RgbaImage>, archive_scan_order : ScanOrder, image_data_decompressor : &mut dyn Read, bitmap_info_decompressor : &mut dyn Read) -> Result<RgbaImage, String>
+    {
+        let next_image = next_image.ok_or_else(|| "There is no next image to predict from".to_string())?;
+
+        let mut cropped_bitmap = vec![0u8; metadata.diff_width as usize * metadata.diff_height as usize];
+        bitmap_info_decompressor.read_exact(&mut cropped_bitmap).map_err(|e| format!("Unable to read bitmap: {}", e))?;
+
+        //start from a copy of the previous image, then fill in the diff rectangle
+        let mut full_image = copy_reference((metadata.output_width, metadata.output_height), prev_image);
+        let (x_offset_to_next_image, y_offset_to_next_image) = get_offset_to_other_image(&full_image, next_image);
+
+        for ((x, y), &bitmap_value) in self.scan_order(archive_scan_order, (metadata.diff_width, metadata.diff_height)).zip(cropped_bitmap.iter()) {
+            let (image_x, image_y) = (x + metadata.x, y + metadata.y);
+            match bitmap_value {
+                0 => {},
+                1 => {
+                    let mut pixel_raw_data = [0u8; 4];
+                    image_data_decompressor.read_exact(&mut pixel_raw_data).map_err(|e| format!("Unable to read image data: {}", e))?;
+                    *full_image.get_pixel_mut(image_x, image_y) = Rgba(pixel_raw_data);
+                },
+                FROM_NEXT_IMAGE => {
+                    *full_image.get_pixel_mut(image_x, image_y) = try_get_pixel((image_x as i64 + x_offset_to_next_image, image_y as i64 + y_offset_to_next_image), next_image)
+                        .ok_or_else(|| format!("Pixel ({}, {}) is taken from outside the next image", image_x, image_y))?;
+                },
+                _ => return Err(format!("Invalid bitmap value {}", bitmap_value)),
+            }
+        }
+
+        Ok(full_image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use common::{CompressedImageInfo, ReferenceTransform};
+    use scan_order::DEFAULT_SCAN_ORDER;
+    use super::{Predictor, BASELINE_STRATEGY, DiffStrategy, STRATEGIES, strategy_by_id, strategy_by_name};
+
+    #[test]
+    fn strategy_ids_are_unique()
+    {
+        for strategy in STRATEGIES.iter() {
+            assert_eq!(strategy_by_id(strategy.id()).unwrap().name(), strategy.name());
+        }
+        assert!(strategy_by_id(255).is_err());
+        assert_eq!(strategy_by_name("motion").unwrap().name(), "motion");
+        assert!(strategy_by_name("unknown").is_err());
+    }
+
+    #[test]
+    fn motion_strategy_stores_only_what_moved()
+    {
+        //a textured arm which moves 3 pixels right and 2 down, on a sprite which doesn't change otherwise. The
+        //background the arm uncovers still has to be stored.
+        let draw = |arm_x : u32, arm_y : u32| RgbaImage::from_fn(48, 40, |x, y| {
+            if (arm_x..arm_x + 12).contains(&x) && (arm_y..arm_y + 6).contains(&y) {
+                Rgba([(x - arm_x) as u8 * 20, (y - arm_y) as u8 * 40, 100, 255])
+            }
+            else {
+                Rgba([10, 10, (x + y) as u8, 255])
+            }
+        });
+        let reference = draw(10, 10);
+        let image = draw(13, 12);
+
+        let motion = strategy_by_name("motion").unwrap();
+        let mut image_data = Vec::new();
+        let mut bitmap = Vec::new();
+        let (crop_region, motion_pixels) = motion.encode(&image, &reference, None, DEFAULT_SCAN_ORDER, &mut image_data, &mut bitmap);
+        let (_, baseline_pixels) = BASELINE_STRATEGY.encode(&image, &reference, None, DEFAULT_SCAN_ORDER, &mut Vec::new(), &mut Vec::new());
+        assert!(motion_pixels * 2 < baseline_pixels, "motion {} baseline {}", motion_pixels, baseline_pixels);
+
+        let info = CompressedImageInfo {
+            start_index : 0,
+            x : crop_region.top_left.0,
+            y : crop_region.top_left.1,
+            diff_width : crop_region.dimensions.0,
+            diff_height : crop_region.dimensions.1,
+            output_width : image.width(),
+            output_height : image.height(),
+            output_path : String::new(),
+            duplicate_of : None,
+            reference_transform : ReferenceTransform::None,
+            strategy : motion.id(),
+        };
+        let decoded = motion.decode(&info, &reference, None, DEFAULT_SCAN_ORDER, &mut &image_data[..], &mut &bitmap[..]).unwrap();
+        assert!(decoded == image);
+    }
+
+    #[test]
+    fn delta_predictor_round_trips()
+    {
+        let pixel = Rgba([3, 200, 0, 255]);
+        for reference_pixel in [None, Some(Rgba([10, 100, 0, 0])), Some(pixel)].iter() {
+            let stored = Predictor::Delta.encode(pixel, *reference_pixel);
+            assert_eq!(Predictor::Delta.decode(stored, *reference_pixel), pixel);
+        }
+        assert_eq!(Predictor::Delta.encode(pixel, Some(pixel)), [0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,288 @@
+use common::{ArchiveHeader, DecompressionInfo, ReferenceTransform};
+use common::FILE_FORMAT_HEADER_LENGTH;
+use strategy::strategy_by_id;
+
+/// Upper bounds on what an archive may ask the extractor to allocate or decode.
+/// Archives are checked against these before any image data is decoded.
+#[derive(Debug, Clone, Copy)]
+pub struct ArchiveLimits {
+    //largest width or height of a single image
+    pub max_image_dimension : u32,
+    //largest width * height of a single image (each pixel takes 4 bytes when decoded)
+    pub max_image_pixels : u64,
+    //largest width * height summed over every image in the archive
+    pub max_total_pixels : u64,
+    //largest size of the uncompressed DecompressionInfo
+    pub max_metadata_bytes : u64,
+    //log2 of the largest window/dictionary the zstd and xz decompressors may allocate
+    pub max_window_log : u32,
+}
+
+impl Default for ArchiveLimits {
+    fn default() -> ArchiveLimits
+    {
+        ArchiveLimits {
+            max_image_dimension : 16384,
+            max_image_pixels : 8192 * 8192,
+            max_total_pixels : 1 << 36,
+            max_metadata_bytes : 256 * 1024 * 1024,
+            max_window_log : 27,
+        }
+    }
+}
+
+/// Checks that every offset and size in the archive's metadata is consistent, so the extractor can trust them:
+/// - the metadata and every solid block lie inside the file, in order and without overlapping
+/// - the blocks cover every image exactly once, in order
+/// - every image's diff rectangle fits inside the image, and image sizes are within the limits
+/// - duplicates refer to an earlier, non-duplicate image
+/// - bidirectional images (B-frames) come after two stored images in their block, which they are predicted from
+pub fn validate_decompression_info(decompression_info : &DecompressionInfo, header : &ArchiveHeader, file_length : u64, limits : &ArchiveLimits) -> Result<(), String>
+{
+    if header.metadata_start < FILE_FORMAT_HEADER_LENGTH as u64 || header.metadata_start > file_length {
+        return Err(format!("Metadata start {} is outside the file (length {})", header.metadata_start, file_length));
+    }
+
+    if header.parity_start != 0 && (header.parity_start < header.metadata_start || header.parity_start > file_length) {
+        return Err(format!("Parity start {} is outside the file (metadata starts at {}, length {})", header.parity_start, header.metadata_start, file_length));
+    }
+
+    // ---------------------------- check the solid blocks ----------------------------
+    let mut expected_block_start = FILE_FORMAT_HEADER_LENGTH as u64;
+    let mut expected_first_image = 0usize;
+    for (block_i, block) in decompression_info.blocks.iter().enumerate()
+    {
+        let offsets_in_order = block.image_data_start == expected_block_start &&
+            block.image_data_start <= block.bitmap_data_start &&
+            block.bitmap_data_start <= block.block_end &&
+            block.block_end <= header.metadata_start;
+
+        if !offsets_in_order {
+            return Err(format!("Solid block {} has invalid offsets (image data {}, bitmap {}, end {}, metadata starts at {})",
+                               block_i, block.image_data_start, block.bitmap_data_start, block.block_end, header.metadata_start));
+        }
+
+        if block.first_image != expected_first_image {
+            return Err(format!("Solid block {} starts at image {}, expected image {}", block_i, block.first_image, expected_first_image));
+        }
+
+        expected_block_start = block.block_end;
+        expected_first_image = block.first_image.checked_add(block.num_images)
+            .ok_or_else(|| format!("Solid block {} has too many images", block_i))?;
+    }
+
+    if expected_first_image != decompression_info.images_info.len() {
+        return Err(format!("Solid blocks contain {} images, but the archive has {} images", expected_first_image, decompression_info.images_info.len()));
+    }
+
+    // ---------------------------- check each image ----------------------------
+    let mut total_pixels = 0u64;
+    for (img_i, metadata) in decompression_info.images_info.iter().enumerate()
+    {
+        if metadata.output_width > limits.max_image_dimension || metadata.output_height > limits.max_image_dimension {
+            return Err(format!("Image {} ({}) is {}x{}, larger than the limit of {} pixels per side",
+                               img_i, metadata.output_path, metadata.output_width, metadata.output_height, limits.max_image_dimension));
+        }
+
+        let image_pixels = metadata.output_width as u64 * metadata.output_height as u64;
+        if image_pixels > limits.max_image_pixels {
+            return Err(format!("Image {} ({}) has {} pixels, more than the limit of {}", img_i, metadata.output_path, image_pixels, limits.max_image_pixels));
+        }
+
+        total_pixels += image_pixels;
+        if total_pixels > limits.max_total_pixels {
+            return Err(format!("Archive has more than the limit of {} pixels in total", limits.max_total_pixels));
+        }
+
+        let diff_fits_horizontally = metadata.x as u64 + metadata.diff_width as u64 <= metadata.output_width as u64;
+        let diff_fits_vertically = metadata.y as u64 + metadata.diff_height as u64 <= metadata.output_height as u64;
+        if !diff_fits_horizontally || !diff_fits_vertically {
+            return Err(format!("Image {} ({}) has a diff region ({},{}) size ({},{}) which doesn't fit inside the {}x{} image",
+                               img_i, metadata.output_path, metadata.x, metadata.y, metadata.diff_width, metadata.diff_height,
+                               metadata.output_width, metadata.output_height));
+        }
+
+        strategy_by_id(metadata.strategy).map_err(|e| format!("Image {} ({}): {}", img_i, metadata.output_path, e))?;
+
+        if let Some(original_index) = metadata.duplicate_of {
+            let original_is_valid = original_index < img_i && decompression_info.images_info[original_index].duplicate_of.is_none();
+            if !original_is_valid {
+                return Err(format!("Image {} ({}) is marked as a duplicate of image {}, which is not an earlier stored image",
+                                   img_i, metadata.output_path, original_index));
+            }
+
+            if metadata.diff_width != 0 || metadata.diff_height != 0 {
+                return Err(format!("Image {} ({}) is a duplicate, but has a diff region", img_i, metadata.output_path));
+            }
+        }
+    }
+
+    // ---------------------------- check the B-frames ----------------------------
+    for block in &decompression_info.blocks {
+        let mut num_references = 0;
+        for img_i in block.first_image..block.first_image + block.num_images {
+            let metadata = &decompression_info.images_info[img_i];
+            if metadata.duplicate_of.is_some() {
+                continue;
+            }
+            if !strategy_by_id(metadata.strategy)?.is_bidirectional() {
+                num_references += 1;
+            }
+            else if num_references < 2 {
+                return Err(format!("Image {} ({}) is predicted from the two images stored before it, but its solid block only has {} before it",
+                                   img_i, metadata.output_path, num_references));
+            }
+            else if metadata.reference_transform != ReferenceTransform::None {
+                return Err(format!("Image {} ({}) is bidirectional, but has a reference transform", img_i, metadata.output_path));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use codec::Codec;
+    use common::{ArchiveHeader, CompressedImageInfo, DecompressionInfo, ReferenceTransform, SolidBlockInfo};
+    use common::FILE_FORMAT_HEADER_LENGTH;
+    use scan_order::DEFAULT_SCAN_ORDER;
+    use strategy::{DiffStrategy, BIDIRECTIONAL_STRATEGY};
+    use super::{validate_decompression_info, ArchiveLimits};
+
+    const FILE_LENGTH : u64 = 1100;
+
+    fn image_info(output_path : &str) -> CompressedImageInfo
+    {
+        CompressedImageInfo {
+            start_index : 0,
+            x : 0,
+            y : 0,
+            diff_width : 4,
+            diff_height : 4,
+            output_width : 4,
+            output_height : 4,
+            output_path : output_path.to_string(),
+            duplicate_of : None,
+            reference_transform : ReferenceTransform::None,
+            strategy : 0,
+        }
+    }
+
+    /// A valid archive of 5 4x4 images: images 0 to 2 in the first solid block, 3 and 4 in the second
+    fn valid_archive() -> (DecompressionInfo, ArchiveHeader)
+    {
+        let first_block_start = FILE_FORMAT_HEADER_LENGTH as u64;
+        let decompression_info = DecompressionInfo {
+            canvas_size : (4, 4),
+            blocks : vec![
+                SolidBlockInfo { first_image : 0, num_images : 3, image_data_start : first_block_start, bitmap_data_start : 300, block_end : 500 },
+                SolidBlockInfo { first_image : 3, num_images : 2, image_data_start : 500, bitmap_data_start : 700, block_end : 900 },
+            ],
+            images_info : (0..5).map(|img_i| image_info(&format!("{}.png", img_i))).collect(),
+        };
+        let header = ArchiveHeader {
+            version : 5,
+            image_codec : Codec::Brotli,
+            bitmap_codec : Codec::Brotli,
+            metadata_codec : Codec::Brotli,
+            metadata_start : 1000,
+            parity_start : 0,
+            scan_order : DEFAULT_SCAN_ORDER,
+            context_coded_bitmap : false,
+        };
+        (decompression_info, header)
+    }
+
+    fn assert_rejected(decompression_info : &DecompressionInfo, header : &ArchiveHeader, limits : &ArchiveLimits, expected_error : &str)
+    {
+        match validate_decompression_info(decompression_info, header, FILE_LENGTH, limits) {
+            Ok(()) => panic!("archive was accepted, expected an error containing {:?}", expected_error),
+            Err(e) => assert!(e.contains(expected_error), "error {:?} doesn't contain {:?}", e, expected_error),
+        }
+    }
+
+    #[test]
+    fn valid_archive_is_accepted()
+    {
+        let (decompression_info, header) = valid_archive();
+        assert_eq!(validate_decompression_info(&decompression_info, &header, FILE_LENGTH, &ArchiveLimits::default()), Ok(()));
+    }
+
+    #[test]
+    fn block_offsets_must_be_in_order_inside_the_file()
+    {
+        let limits = ArchiveLimits::default();
+
+        let (mut decompression_info, header) = valid_archive();
+        decompression_info.blocks[1].image_data_start = 700;
+        decompression_info.blocks[1].bitmap_data_start = 500;
+        assert_rejected(&decompression_info, &header, &limits, "Solid block 1 has invalid offsets");
+
+        let (mut decompression_info, header) = valid_archive();
+        decompression_info.blocks[1].block_end = 1050;
+        assert_rejected(&decompression_info, &header, &limits, "Solid block 1 has invalid offsets");
+
+        let (decompression_info, mut header) = valid_archive();
+        header.metadata_start = FILE_LENGTH + 1;
+        assert_rejected(&decompression_info, &header, &limits, "outside the file");
+    }
+
+    #[test]
+    fn diff_rectangle_must_fit_inside_the_image()
+    {
+        let (mut decompression_info, header) = valid_archive();
+        decompression_info.images_info[2].x = 1;
+        assert_rejected(&decompression_info, &header, &ArchiveLimits::default(), "doesn't fit inside the 4x4 image");
+
+        let (mut decompression_info, header) = valid_archive();
+        decompression_info.images_info[2].y = u32::MAX;
+        assert_rejected(&decompression_info, &header, &ArchiveLimits::default(), "doesn't fit inside the 4x4 image");
+    }
+
+    #[test]
+    fn image_sizes_must_be_within_the_limits()
+    {
+        let (decompression_info, header) = valid_archive();
+        assert_rejected(&decompression_info, &header, &ArchiveLimits { max_image_dimension : 3, ..ArchiveLimits::default() }, "pixels per side");
+        assert_rejected(&decompression_info, &header, &ArchiveLimits { max_image_pixels : 15, ..ArchiveLimits::default() }, "more than the limit of 15");
+        assert_rejected(&decompression_info, &header, &ArchiveLimits { max_total_pixels : 5 * 16 - 1, ..ArchiveLimits::default() }, "pixels in total");
+        assert_eq!(validate_decompression_info(&decompression_info, &header, FILE_LENGTH, &ArchiveLimits { max_total_pixels : 5 * 16, ..ArchiveLimits::default() }), Ok(()));
+    }
+
+    #[test]
+    fn duplicates_must_refer_to_an_earlier_image()
+    {
+        for &original_index in [2, 4].iter() {
+            let (mut decompression_info, header) = valid_archive();
+            let duplicate = &mut decompression_info.images_info[2];
+            duplicate.duplicate_of = Some(original_index);
+            duplicate.diff_width = 0;
+            duplicate.diff_height = 0;
+            assert_rejected(&decompression_info, &header, &ArchiveLimits::default(), "which is not an earlier stored image");
+        }
+    }
+
+    #[test]
+    fn b_frames_need_two_stored_images_before_them()
+    {
+        //the third image of the first block is predicted from the two before it
+        let (mut decompression_info, header) = valid_archive();
+        decompression_info.images_info[2].strategy = BIDIRECTIONAL_STRATEGY.id();
+        assert_eq!(validate_decompression_info(&decompression_info, &header, FILE_LENGTH, &ArchiveLimits::default()), Ok(()));
+
+        //the first image of the second block has nothing before it
+        let (mut decompression_info, header) = valid_archive();
+        decompression_info.images_info[3].strategy = BIDIRECTIONAL_STRATEGY.id();
+        assert_rejected(&decompression_info, &header, &ArchiveLimits::default(), "only has 0 before it");
+
+        //a duplicate of an image in another block isn't stored in this one
+        let (mut decompression_info, header) = valid_archive();
+        let duplicate = &mut decompression_info.images_info[3];
+        duplicate.duplicate_of = Some(0);
+        duplicate.diff_width = 0;
+        duplicate.diff_height = 0;
+        decompression_info.images_info[4].strategy = BIDIRECTIONAL_STRATEGY.id();
+        assert_rejected(&decompression_info, &header, &ArchiveLimits::default(), "only has 0 before it");
+    }
+}
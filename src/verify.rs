@@ -0,0 +1,329 @@
+//standard uses
+use std::fs;
+use std::path::Path;
+
+//non-standard use
+use image;
+use image::{Rgba, RgbaImage};
+use serde_json;
+
+use common::FileTypeIterator;
+use common::remove_alpha;
+
+//gap between the panels of a diff image
+const DIFF_IMAGE_GAP : u32 = 2;
+
+/// The result of comparing one input image with the extracted copy
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ImageVerification {
+    //every pixel matches exactly
+    ExactMatch,
+    //the only differences are in pixels which are invisible (alpha 0) in both images.
+    //oxipng can change these, so this isn't counted as a failure
+    InvisibleMatch { invisible_differences : u64 },
+    //some visible pixels are different. first_difference is the first one in row order
+    Mismatch { different_pixels : u64, first_difference : (u32, u32), expected_pixel : [u8; 4], actual_pixel : [u8; 4] },
+    //the extracted image has a different width/height, so the pixels weren't compared
+    DimensionMismatch { expected : (u32, u32), actual : (u32, u32) },
+    //the extracted image doesn't exist, or can't be decoded
+    Missing { error : String },
+    //the input image itself can't be decoded
+    InputUnreadable { error : String },
+}
+
+impl ImageVerification {
+    pub fn is_failure(&self) -> bool
+    {
+        !matches!(self, ImageVerification::ExactMatch | ImageVerification::InvisibleMatch { .. })
+    }
+
+    pub fn name(&self) -> &'static str
+    {
+        match self {
+            ImageVerification::ExactMatch => "exact match",
+            ImageVerification::InvisibleMatch { .. } => "invisible pixels differ",
+            ImageVerification::Mismatch { .. } => "MISMATCH",
+            ImageVerification::DimensionMismatch { .. } => "DIMENSION MISMATCH",
+            ImageVerification::Missing { .. } => "MISSING",
+            ImageVerification::InputUnreadable { .. } => "INPUT UNREADABLE",
+        }
+    }
+}
+
+/// The verification result of one file, with its path relative to the input/output folders
+#[derive(Serialize, Debug, Clone)]
+pub struct FileVerification {
+    pub path : String,
+    #[serde(flatten)]
+    pub result : ImageVerification,
+    //why the diff image of a mismatched image couldn't be saved (verification carries on with the other images)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff_image_error : Option<String>,
+}
+
+/// Per-file results of verifying every image in the input folder
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct VerificationReport {
+    pub files : Vec<FileVerification>,
+}
+
+impl VerificationReport {
+    pub fn num_failures(&self) -> usize
+    {
+        self.files.iter().filter(|file| file.result.is_failure()).count()
+    }
+
+    pub fn all_exact(&self) -> bool
+    {
+        self.files.iter().all(|file| file.result == ImageVerification::ExactMatch)
+    }
+
+    /// The whole report as JSON, with the totals before the per-file results
+    pub fn to_json(&self) -> String
+    {
+        #[derive(Serialize)]
+        struct JsonReport<'a> {
+            num_images : usize,
+            num_failures : usize,
+            all_exact : bool,
+            files : &'a [FileVerification],
+        }
+
+        serde_json::to_string_pretty(&JsonReport {
+            num_images : self.files.len(),
+            num_failures : self.num_failures(),
+            all_exact : self.all_exact(),
+            files : &self.files,
+        }).unwrap()
+    }
+
+    /// Number of files with each kind of result, in the order they first appear
+    pub fn summary(&self) -> Vec<(&'static str, usize)>
+    {
+        let mut summary : Vec<(&'static str, usize)> = Vec::new();
+        for file in &self.files {
+            match summary.iter_mut().find(|(name, _)| *name == file.result.name()) {
+                Some((_, count)) => *count += 1,
+                None => summary.push((file.result.name(), 1)),
+            }
+        }
+        summary
+    }
+}
+
+/// Compares two images pixel by pixel, after checking they have the same dimensions
+pub fn compare_images(expected_image : &RgbaImage, actual_image : &RgbaImage) -> ImageVerification
+{
+    if expected_image.dimensions() != actual_image.dimensions() {
+        return ImageVerification::DimensionMismatch {
+            expected : expected_image.dimensions(),
+            actual : actual_image.dimensions(),
+        };
+    }
+
+    let mut invisible_differences = 0;
+    let mut different_pixels = 0;
+    let mut first_difference = None;
+    for ((x, y, expected_pixel), actual_pixel) in expected_image.enumerate_pixels().zip(actual_image.pixels())
+    {
+        if expected_pixel == actual_pixel {
+            continue;
+        }
+
+        //if both pixel's alpha values are 0, it is an invisible pixel
+        if expected_pixel[3] == 0 && actual_pixel[3] == 0 {
+            invisible_differences += 1;
+        }
+        else {
+            different_pixels += 1;
+            first_difference.get_or_insert((x, y, expected_pixel.0, actual_pixel.0));
+        }
+    }
+
+    match first_difference {
+        Some((x, y, expected_pixel, actual_pixel)) => ImageVerification::Mismatch {
+            different_pixels,
+            first_difference : (x, y),
+            expected_pixel,
+            actual_pixel,
+        },
+        None if invisible_differences > 0 => ImageVerification::InvisibleMatch { invisible_differences },
+        None => ImageVerification::ExactMatch,
+    }
+}
+
+/// Draws a picture of the differences between two images of the same size. From left to right, the panels are:
+/// - the expected image, with alpha ignored (so the colour of transparent pixels can be seen)
+/// - the actual image, with alpha ignored
+/// - the expected image dimmed to gray, with changed visible pixels in red and changed invisible pixels in blue
+/// - the expected alpha channel in gray, with pixels whose alpha changed in magenta
+pub fn render_diff_image(expected_image : &RgbaImage, actual_image : &RgbaImage) -> RgbaImage
+{
+    let (width, height) = expected_image.dimensions();
+    let mut diff_image = RgbaImage::from_pixel(width * 4 + DIFF_IMAGE_GAP * 3, height, Rgba([128, 128, 128, 255]));
+
+    let mut expected_no_alpha = expected_image.clone();
+    remove_alpha(&mut expected_no_alpha);
+    let mut actual_no_alpha = actual_image.clone();
+    remove_alpha(&mut actual_no_alpha);
+    image::imageops::replace(&mut diff_image, &expected_no_alpha, 0, 0);
+    image::imageops::replace(&mut diff_image, &actual_no_alpha, (width + DIFF_IMAGE_GAP) as i64, 0);
+
+    let highlight_x = (width + DIFF_IMAGE_GAP) * 2;
+    let alpha_x = (width + DIFF_IMAGE_GAP) * 3;
+    for ((x, y, expected_pixel), actual_pixel) in expected_image.enumerate_pixels().zip(actual_image.pixels())
+    {
+        let highlight_pixel = if expected_pixel == actual_pixel {
+            let luma = (expected_pixel[0] as u32 * 3 + expected_pixel[1] as u32 * 6 + expected_pixel[2] as u32) / 10;
+            let dimmed = (luma * expected_pixel[3] as u32 / 255 / 3) as u8;
+            Rgba([dimmed, dimmed, dimmed, 255])
+        }
+        else if expected_pixel[3] == 0 && actual_pixel[3] == 0 {
+            Rgba([0, 64, 255, 255])
+        }
+        else {
+            Rgba([255, 0, 0, 255])
+        };
+        diff_image.put_pixel(highlight_x + x, y, highlight_pixel);
+
+        let alpha_pixel = if expected_pixel[3] == actual_pixel[3] {
+            Rgba([expected_pixel[3], expected_pixel[3], expected_pixel[3], 255])
+        }
+        else {
+            Rgba([255, 0, 255, 255])
+        };
+        diff_image.put_pixel(alpha_x + x, y, alpha_pixel);
+    }
+
+    diff_image
+}
+
+/// Renders the diff image of a mismatched image (see render_diff_image) and saves it, creating its folder(s)
+fn save_diff_image(expected_image : &RgbaImage, actual_image : &RgbaImage, diff_image_path : &Path) -> Result<(), String>
+{
+    if let Some(parent) = diff_image_path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Unable to create folder {}: {}", parent.display(), e))?;
+    }
+    render_diff_image(expected_image, actual_image).save(diff_image_path)
+        .map_err(|e| format!("Unable to save diff image {}: {}", diff_image_path.display(), e))
+}
+
+/// Compares every .png image in the input folder with the image at the same path in the output folder.
+/// If diff_folder is given, a diff image (see render_diff_image) is saved there for each mismatched image. A diff
+/// image which can't be saved is recorded in the file's result, and the other images are still verified.
+pub fn verify_images(input_folder : &str, output_folder : &str, diff_folder : Option<&str>) -> VerificationReport
+{
+    let mut report = VerificationReport::default();
+    for ent in FileTypeIterator::new(input_folder, "png")
+    {
+        let path_relative_to_input_folder = ent.path().strip_prefix(input_folder).unwrap();
+        let output_folder_image_path = Path::new(output_folder).join(path_relative_to_input_folder);
+        let mut diff_image_error = None;
+
+        let result = match (image::open(ent.path()), image::open(&output_folder_image_path)) {
+            (Err(e), _) => ImageVerification::InputUnreadable { error : e.to_string() },
+            (_, Err(e)) => ImageVerification::Missing { error : e.to_string() },
+            (Ok(input_image), Ok(output_image)) => {
+                let (input_image, output_image) = (input_image.to_rgba8(), output_image.to_rgba8());
+                let result = compare_images(&input_image, &output_image);
+                if let (Some(diff_folder), ImageVerification::Mismatch { .. }) = (diff_folder, &result) {
+                    let diff_image_path = Path::new(diff_folder).join(path_relative_to_input_folder);
+                    println!("Saving diff image to {}", diff_image_path.display());
+                    if let Err(e) = save_diff_image(&input_image, &output_image, &diff_image_path) {
+                        println!("ERROR: {}", e);
+                        diff_image_error = Some(e);
+                    }
+                }
+                result
+            },
+        };
+
+        report.files.push(FileVerification {
+            path : path_relative_to_input_folder.to_string_lossy().replace('\\', "/"),
+            result,
+            diff_image_error,
+        });
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use image::{Rgba, RgbaImage};
+
+    use common::ScratchFolder;
+    use super::{compare_images, render_diff_image, verify_images, ImageVerification, DIFF_IMAGE_GAP};
+
+    #[test]
+    fn compare_images_results()
+    {
+        let image = RgbaImage::from_fn(4, 3, |x, y| Rgba([x as u8, y as u8, 0, if x == 0 { 0 } else { 255 }]));
+        assert_eq!(compare_images(&image, &image), ImageVerification::ExactMatch);
+
+        let mut invisible_changed = image.clone();
+        invisible_changed.put_pixel(0, 1, Rgba([9, 9, 9, 0]));
+        assert_eq!(compare_images(&image, &invisible_changed), ImageVerification::InvisibleMatch { invisible_differences : 1 });
+
+        let mut visible_changed = invisible_changed.clone();
+        visible_changed.put_pixel(3, 1, Rgba([1, 2, 3, 4]));
+        visible_changed.put_pixel(2, 2, Rgba([1, 2, 3, 4]));
+        assert_eq!(compare_images(&image, &visible_changed), ImageVerification::Mismatch {
+            different_pixels : 2,
+            first_difference : (3, 1),
+            expected_pixel : [3, 1, 0, 255],
+            actual_pixel : [1, 2, 3, 4],
+        });
+    }
+
+    #[test]
+    fn compare_images_checks_dimensions_first()
+    {
+        //same number of pixels, so comparing the raw buffers would look like a pixel mismatch
+        let wide = RgbaImage::new(6, 2);
+        let tall = RgbaImage::new(2, 6);
+        assert_eq!(compare_images(&wide, &tall), ImageVerification::DimensionMismatch { expected : (6, 2), actual : (2, 6) });
+        assert!(compare_images(&wide, &tall).is_failure());
+    }
+
+    #[test]
+    fn diff_image_highlights_changed_pixels()
+    {
+        let expected = RgbaImage::from_pixel(3, 2, Rgba([10, 20, 30, 255]));
+        let mut actual = expected.clone();
+        actual.put_pixel(1, 1, Rgba([10, 20, 30, 128]));
+
+        let diff_image = render_diff_image(&expected, &actual);
+        assert_eq!(diff_image.dimensions(), (3 * 4 + DIFF_IMAGE_GAP * 3, 2));
+
+        let highlight_x = (3 + DIFF_IMAGE_GAP) * 2;
+        let alpha_x = (3 + DIFF_IMAGE_GAP) * 3;
+        assert_eq!(*diff_image.get_pixel(highlight_x + 1, 1), Rgba([255, 0, 0, 255]));
+        assert_ne!(*diff_image.get_pixel(highlight_x, 1), Rgba([255, 0, 0, 255]));
+        assert_eq!(*diff_image.get_pixel(alpha_x + 1, 1), Rgba([255, 0, 255, 255]));
+        assert_eq!(*diff_image.get_pixel(alpha_x, 1), Rgba([255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn unsaved_diff_image_is_reported()
+    {
+        //the diff folder is a file, so no diff image can be saved in it
+        let scratch_folder = ScratchFolder::new().unwrap();
+        let (input_folder, output_folder, diff_folder) = (scratch_folder.path.join("input"), scratch_folder.path.join("output"), scratch_folder.path.join("diffs"));
+        fs::create_dir(&input_folder).unwrap();
+        fs::create_dir(&output_folder).unwrap();
+        fs::write(&diff_folder, b"").unwrap();
+        for name in ["a.png", "b.png"].iter() {
+            RgbaImage::from_pixel(2, 2, Rgba([1, 2, 3, 255])).save(input_folder.join(name)).unwrap();
+            RgbaImage::from_pixel(2, 2, Rgba([4, 5, 6, 255])).save(output_folder.join(name)).unwrap();
+        }
+
+        let report = verify_images(input_folder.to_str().unwrap(), output_folder.to_str().unwrap(), Some(diff_folder.to_str().unwrap()));
+        assert_eq!(report.files.len(), 2);
+        assert_eq!(report.num_failures(), 2);
+        assert!(report.files.iter().all(|file| file.diff_image_error.is_some()));
+        assert!(report.to_json().contains("diff_image_error"));
+    }
+}